@@ -0,0 +1,149 @@
+use std::fmt::Write;
+use std::io::{self, Cursor};
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use huw_stack_machine::{parse, StackMachine};
+
+// Every program below has a fixed size so results stay comparable between
+// commits. Changing a program invalidates saved baselines for its benchmark.
+
+const ITERATIONS: u64 = 100000;
+
+// Eight arithmetic instructions per iteration that leave the counter where it
+// started, then an increment and the loop test.
+const ARITHMETIC: &str = "
+pushi 0
+label loop
+pushi 3
+muli
+pushi 7
+addi
+pushi 7
+subi
+pushi 3
+divi
+pushi 1
+addi
+pushi 100000
+jl loop
+printl
+";
+
+// Five unconditional jumps and a taken `jnz` per iteration.
+const JUMPS: &str = "
+pushi 0
+label loop
+j a
+label c
+j d
+label b
+j c
+label a
+j b
+label d
+pushi 1
+addi
+pushb true
+jnz next
+label next
+pushi 100000
+jl loop
+printl
+";
+
+// Reads and prints one int per iteration.
+const IO: &str = "
+pushi 0
+label loop
+inputi
+printl
+pop
+pushi 1
+addi
+pushi 10000
+jl loop
+";
+
+const IO_LINES: u64 = 10000;
+
+// The programs in the repository that the README quotes timings for: the
+// counting loop in `performance1.hsc` and the same loop in registers in
+// `performance2.hsc`, and a sum of squares on the stack in `squares1.hsc` and
+// in registers in `squares2.hsc`.
+const PERFORMANCE1: &str = include_str!("../performance1.hsc");
+const PERFORMANCE2: &str = include_str!("../performance2.hsc");
+const SQUARES1: &str = include_str!("../squares1.hsc");
+const SQUARES2: &str = include_str!("../squares2.hsc");
+
+const PERFORMANCE_ITERATIONS: u64 = 200000;
+const SQUARES_ITERATIONS: u64 = 100000;
+
+// Builds a source file of `blocks` copies of a block that mixes labels,
+// jumps, typed literals, strings and comments.
+fn large_source(blocks: usize) -> String {
+    let mut source = String::new();
+    for n in 0..blocks {
+        writeln!(source, "label block{}", n).unwrap();
+        writeln!(source, "pushi {}    ; counter", n).unwrap();
+        writeln!(source, "pushf 1.5").unwrap();
+        writeln!(source, "pushl 4000000000").unwrap();
+        writeln!(source, "pushs \"block # {}\"", n).unwrap();
+        writeln!(source, "drop").unwrap();
+        writeln!(source, "mov r1 r2").unwrap();
+        writeln!(source, "j block{}", (n + 1) % blocks).unwrap();
+    }
+    source
+}
+
+// Returns a machine with `source` loaded, reading `input` and discarding
+// everything it prints.
+fn machine(source: &str, input: &str) -> StackMachine {
+    let mut sm = StackMachine::with_io(Box::new(Cursor::new(input.to_string())), Box::new(io::sink()));
+    sm.load_source(source).expect("benchmark program should load");
+    sm
+}
+
+fn parsing(c: &mut Criterion) {
+    let source = large_source(1000);
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(source.len() as u64));
+    group.bench_function("parse", |b| b.iter(|| parse(black_box(&source), None).unwrap()));
+    group.bench_function("load", |b| {
+        let mut sm = machine("", "");
+        b.iter(|| sm.load_source(black_box(&source)).unwrap())
+    });
+    group.finish();
+}
+
+fn run(c: &mut Criterion, name: &str, source: &str, input: &str, elements: u64) {
+    let mut group = c.benchmark_group("run");
+    group.throughput(Throughput::Elements(elements));
+    group.bench_function(name, |b| b.iter_batched(|| machine(source, input), |mut sm| sm.run().unwrap(), BatchSize::SmallInput));
+    group.finish();
+}
+
+fn arithmetic(c: &mut Criterion) {
+    run(c, "arithmetic", ARITHMETIC, "", ITERATIONS);
+}
+
+fn jumps(c: &mut Criterion) {
+    run(c, "jumps", JUMPS, "", ITERATIONS);
+}
+
+fn performance(c: &mut Criterion) {
+    run(c, "performance1", PERFORMANCE1, "", PERFORMANCE_ITERATIONS);
+    run(c, "performance2", PERFORMANCE2, "", PERFORMANCE_ITERATIONS);
+}
+
+fn squares(c: &mut Criterion) {
+    run(c, "squares1", SQUARES1, "", SQUARES_ITERATIONS);
+    run(c, "squares2", SQUARES2, "", SQUARES_ITERATIONS);
+}
+
+fn input_output(c: &mut Criterion) {
+    let input: String = (0..IO_LINES).map(|n| format!("{}\n", n)).collect();
+    run(c, "io", IO, &input, IO_LINES);
+}
+
+criterion_group!(benches, parsing, arithmetic, jumps, performance, squares, input_output);
+criterion_main!(benches);
@@ -0,0 +1,305 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::value::ValueError;
+
+/// How integer instructions behave when a result does not fit in its type.
+/// The same mode gives the same results in debug and release builds.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum ArithmeticMode {
+    /// Overflow fails with `VmError::IntegerOverflow`.
+    #[default]
+    CHECKED,
+    /// Results wrap around at the boundary of the type.
+    WRAPPING,
+    /// Results are clamped to the minimum or maximum of the type.
+    SATURATING,
+}
+
+/// Integer types the machine does arithmetic on.
+pub trait Integer: Copy {
+    fn checked_add(self, other: Self) -> Option<Self>;
+    fn wrapping_add(self, other: Self) -> Self;
+    fn saturating_add(self, other: Self) -> Self;
+    fn checked_sub(self, other: Self) -> Option<Self>;
+    fn wrapping_sub(self, other: Self) -> Self;
+    fn saturating_sub(self, other: Self) -> Self;
+    fn checked_mul(self, other: Self) -> Option<Self>;
+    fn wrapping_mul(self, other: Self) -> Self;
+    fn saturating_mul(self, other: Self) -> Self;
+    fn checked_div(self, other: Self) -> Option<Self>;
+    fn wrapping_div(self, other: Self) -> Self;
+    fn saturating_div(self, other: Self) -> Self;
+    fn wrapping_rem(self, other: Self) -> Self;
+    fn checked_neg(self) -> Option<Self>;
+    fn wrapping_neg(self) -> Self;
+    fn saturating_neg(self) -> Self;
+    fn checked_abs(self) -> Option<Self>;
+    fn wrapping_abs(self) -> Self;
+    fn saturating_abs(self) -> Self;
+}
+
+macro_rules! impl_integer {
+    ($($t:ty),*) => {$(
+        impl Integer for $t {
+            fn checked_add(self, other: Self) -> Option<Self> { <$t>::checked_add(self, other) }
+            fn wrapping_add(self, other: Self) -> Self { <$t>::wrapping_add(self, other) }
+            fn saturating_add(self, other: Self) -> Self { <$t>::saturating_add(self, other) }
+            fn checked_sub(self, other: Self) -> Option<Self> { <$t>::checked_sub(self, other) }
+            fn wrapping_sub(self, other: Self) -> Self { <$t>::wrapping_sub(self, other) }
+            fn saturating_sub(self, other: Self) -> Self { <$t>::saturating_sub(self, other) }
+            fn checked_mul(self, other: Self) -> Option<Self> { <$t>::checked_mul(self, other) }
+            fn wrapping_mul(self, other: Self) -> Self { <$t>::wrapping_mul(self, other) }
+            fn saturating_mul(self, other: Self) -> Self { <$t>::saturating_mul(self, other) }
+            fn checked_div(self, other: Self) -> Option<Self> { <$t>::checked_div(self, other) }
+            fn wrapping_div(self, other: Self) -> Self { <$t>::wrapping_div(self, other) }
+            fn saturating_div(self, other: Self) -> Self { <$t>::saturating_div(self, other) }
+            fn wrapping_rem(self, other: Self) -> Self { <$t>::wrapping_rem(self, other) }
+            fn checked_neg(self) -> Option<Self> { <$t>::checked_neg(self) }
+            fn wrapping_neg(self) -> Self { <$t>::wrapping_neg(self) }
+            fn saturating_neg(self) -> Self { <$t>::saturating_neg(self) }
+            fn checked_abs(self) -> Option<Self> { <$t>::checked_abs(self) }
+            fn wrapping_abs(self) -> Self { <$t>::wrapping_abs(self) }
+            fn saturating_abs(self) -> Self { <$t>::saturating_abs(self) }
+        }
+    )*};
+}
+
+impl_integer!(i32, i64);
+
+// Each operation returns `None` on overflow, which only happens in CHECKED
+// mode. Division by zero is checked by the caller before dividing.
+impl ArithmeticMode {
+    pub fn add<T: Integer>(self, a: T, b: T) -> Option<T> {
+        match self {
+            ArithmeticMode::CHECKED => a.checked_add(b),
+            ArithmeticMode::WRAPPING => Some(a.wrapping_add(b)),
+            ArithmeticMode::SATURATING => Some(a.saturating_add(b)),
+        }
+    }
+
+    pub fn sub<T: Integer>(self, a: T, b: T) -> Option<T> {
+        match self {
+            ArithmeticMode::CHECKED => a.checked_sub(b),
+            ArithmeticMode::WRAPPING => Some(a.wrapping_sub(b)),
+            ArithmeticMode::SATURATING => Some(a.saturating_sub(b)),
+        }
+    }
+
+    pub fn mul<T: Integer>(self, a: T, b: T) -> Option<T> {
+        match self {
+            ArithmeticMode::CHECKED => a.checked_mul(b),
+            ArithmeticMode::WRAPPING => Some(a.wrapping_mul(b)),
+            ArithmeticMode::SATURATING => Some(a.saturating_mul(b)),
+        }
+    }
+
+    pub fn div<T: Integer>(self, a: T, b: T) -> Option<T> {
+        match self {
+            ArithmeticMode::CHECKED => a.checked_div(b),
+            ArithmeticMode::WRAPPING => Some(a.wrapping_div(b)),
+            ArithmeticMode::SATURATING => Some(a.saturating_div(b)),
+        }
+    }
+
+    // The remainder of MIN by -1 is 0, so it never overflows.
+    pub fn rem<T: Integer>(self, a: T, b: T) -> Option<T> {
+        Some(a.wrapping_rem(b))
+    }
+
+    pub fn neg<T: Integer>(self, a: T) -> Option<T> {
+        match self {
+            ArithmeticMode::CHECKED => a.checked_neg(),
+            ArithmeticMode::WRAPPING => Some(a.wrapping_neg()),
+            ArithmeticMode::SATURATING => Some(a.saturating_neg()),
+        }
+    }
+
+    pub fn abs<T: Integer>(self, a: T) -> Option<T> {
+        match self {
+            ArithmeticMode::CHECKED => a.checked_abs(),
+            ArithmeticMode::WRAPPING => Some(a.wrapping_abs()),
+            ArithmeticMode::SATURATING => Some(a.saturating_abs()),
+        }
+    }
+
+    /// Narrows a 64-bit integer to 32 bits.
+    pub fn narrow(self, a: i64) -> Option<i32> {
+        match self {
+            ArithmeticMode::CHECKED => i32::try_from(a).ok(),
+            ArithmeticMode::WRAPPING => Some(a as i32),
+            ArithmeticMode::SATURATING => Some(a.clamp(i32::MIN as i64, i32::MAX as i64) as i32),
+        }
+    }
+}
+
+/// Numbers the arithmetic instructions work on. Integers follow the
+/// arithmetic mode; floats follow IEEE 754 and have no bitwise operations.
+pub trait Numeric: Copy {
+    fn add(self, other: Self, mode: ArithmeticMode) -> Result<Self, ValueError>;
+    fn sub(self, other: Self, mode: ArithmeticMode) -> Result<Self, ValueError>;
+    fn mul(self, other: Self, mode: ArithmeticMode) -> Result<Self, ValueError>;
+    fn div(self, other: Self, mode: ArithmeticMode) -> Result<Self, ValueError>;
+    fn rem(self, other: Self, mode: ArithmeticMode) -> Result<Self, ValueError>;
+    fn neg(self, mode: ArithmeticMode) -> Result<Self, ValueError>;
+    fn abs(self, mode: ArithmeticMode) -> Result<Self, ValueError>;
+    fn and(self, other: Self) -> Result<Self, ValueError>;
+    fn or(self, other: Self) -> Result<Self, ValueError>;
+    fn xor(self, other: Self) -> Result<Self, ValueError>;
+    fn not(self) -> Result<Self, ValueError>;
+    fn shl(self, amount: Self) -> Result<Self, ValueError>;
+    fn shr(self, amount: Self) -> Result<Self, ValueError>;
+    fn ushr(self, amount: Self) -> Result<Self, ValueError>;
+}
+
+macro_rules! impl_numeric_integer {
+    ($($t:ty => $u:ty),*) => {$(
+        impl Numeric for $t {
+            fn add(self, other: Self, mode: ArithmeticMode) -> Result<Self, ValueError> {
+                mode.add(self, other).ok_or(ValueError::Overflow)
+            }
+            fn sub(self, other: Self, mode: ArithmeticMode) -> Result<Self, ValueError> {
+                mode.sub(self, other).ok_or(ValueError::Overflow)
+            }
+            fn mul(self, other: Self, mode: ArithmeticMode) -> Result<Self, ValueError> {
+                mode.mul(self, other).ok_or(ValueError::Overflow)
+            }
+            fn div(self, other: Self, mode: ArithmeticMode) -> Result<Self, ValueError> {
+                if other == 0 {
+                    return Err(ValueError::DivideByZero);
+                }
+                mode.div(self, other).ok_or(ValueError::Overflow)
+            }
+            fn rem(self, other: Self, mode: ArithmeticMode) -> Result<Self, ValueError> {
+                if other == 0 {
+                    return Err(ValueError::DivideByZero);
+                }
+                mode.rem(self, other).ok_or(ValueError::Overflow)
+            }
+            fn neg(self, mode: ArithmeticMode) -> Result<Self, ValueError> {
+                mode.neg(self).ok_or(ValueError::Overflow)
+            }
+            fn abs(self, mode: ArithmeticMode) -> Result<Self, ValueError> {
+                mode.abs(self).ok_or(ValueError::Overflow)
+            }
+            fn and(self, other: Self) -> Result<Self, ValueError> { Ok(self & other) }
+            fn or(self, other: Self) -> Result<Self, ValueError> { Ok(self | other) }
+            fn xor(self, other: Self) -> Result<Self, ValueError> { Ok(self ^ other) }
+            fn not(self) -> Result<Self, ValueError> { Ok(!self) }
+            fn shl(self, amount: Self) -> Result<Self, ValueError> { Ok(self << shift_amount(amount as i64, <$t>::BITS)?) }
+            fn shr(self, amount: Self) -> Result<Self, ValueError> { Ok(self >> shift_amount(amount as i64, <$t>::BITS)?) }
+            fn ushr(self, amount: Self) -> Result<Self, ValueError> {
+                Ok(((self as $u) >> shift_amount(amount as i64, <$t>::BITS)?) as $t)
+            }
+        }
+    )*};
+}
+
+impl_numeric_integer!(i32 => u32, i64 => u64);
+
+macro_rules! impl_numeric_float {
+    ($($t:ty),*) => {$(
+        impl Numeric for $t {
+            fn add(self, other: Self, _: ArithmeticMode) -> Result<Self, ValueError> { Ok(self + other) }
+            fn sub(self, other: Self, _: ArithmeticMode) -> Result<Self, ValueError> { Ok(self - other) }
+            fn mul(self, other: Self, _: ArithmeticMode) -> Result<Self, ValueError> { Ok(self * other) }
+            fn div(self, other: Self, _: ArithmeticMode) -> Result<Self, ValueError> { Ok(self / other) }
+            fn rem(self, other: Self, _: ArithmeticMode) -> Result<Self, ValueError> { Ok(self % other) }
+            fn neg(self, _: ArithmeticMode) -> Result<Self, ValueError> { Ok(-self) }
+            fn abs(self, _: ArithmeticMode) -> Result<Self, ValueError> { Ok(<$t>::abs(self)) }
+            fn and(self, _: Self) -> Result<Self, ValueError> { Err(ValueError::TypeMismatch) }
+            fn or(self, _: Self) -> Result<Self, ValueError> { Err(ValueError::TypeMismatch) }
+            fn xor(self, _: Self) -> Result<Self, ValueError> { Err(ValueError::TypeMismatch) }
+            fn not(self) -> Result<Self, ValueError> { Err(ValueError::TypeMismatch) }
+            fn shl(self, _: Self) -> Result<Self, ValueError> { Err(ValueError::TypeMismatch) }
+            fn shr(self, _: Self) -> Result<Self, ValueError> { Err(ValueError::TypeMismatch) }
+            fn ushr(self, _: Self) -> Result<Self, ValueError> { Err(ValueError::TypeMismatch) }
+        }
+    )*};
+}
+
+impl_numeric_float!(f32, f64);
+
+// Shifts by the width of the type or more, or by a negative amount, are
+// errors rather than being masked like the `<<` and `>>` operators would.
+fn shift_amount(amount: i64, bits: u32) -> Result<u32, ValueError> {
+    match u32::try_from(amount) {
+        Ok(amount) if amount < bits => Ok(amount),
+        _ => Err(ValueError::ShiftOutOfRange(amount)),
+    }
+}
+
+/// Operations taking two values of the same type.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum BinaryOp {
+    ADD,
+    SUB,
+    MUL,
+    DIV,
+    REM,
+    AND,
+    OR,
+    XOR,
+    SHL,
+    SHR,
+    USHR,
+}
+
+impl BinaryOp {
+    pub fn apply<T: Numeric>(self, a: T, b: T, mode: ArithmeticMode) -> Result<T, ValueError> {
+        match self {
+            BinaryOp::ADD => a.add(b, mode),
+            BinaryOp::SUB => a.sub(b, mode),
+            BinaryOp::MUL => a.mul(b, mode),
+            BinaryOp::DIV => a.div(b, mode),
+            BinaryOp::REM => a.rem(b, mode),
+            BinaryOp::AND => a.and(b),
+            BinaryOp::OR => a.or(b),
+            BinaryOp::XOR => a.xor(b),
+            BinaryOp::SHL => a.shl(b),
+            BinaryOp::SHR => a.shr(b),
+            BinaryOp::USHR => a.ushr(b),
+        }
+    }
+}
+
+/// Operations taking a single value.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum UnaryOp {
+    NEG,
+    ABS,
+    NOT,
+}
+
+impl UnaryOp {
+    pub fn apply<T: Numeric>(self, a: T, mode: ArithmeticMode) -> Result<T, ValueError> {
+        match self {
+            UnaryOp::NEG => a.neg(mode),
+            UnaryOp::ABS => a.abs(mode),
+            UnaryOp::NOT => a.not(),
+        }
+    }
+}
+
+impl FromStr for ArithmeticMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ArithmeticMode, String> {
+        match s {
+            "checked" => Ok(ArithmeticMode::CHECKED),
+            "wrapping" => Ok(ArithmeticMode::WRAPPING),
+            "saturating" => Ok(ArithmeticMode::SATURATING),
+            _ => Err(format!("Unknown arithmetic mode '{}', expected checked, wrapping or saturating", s)),
+        }
+    }
+}
+
+impl fmt::Display for ArithmeticMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArithmeticMode::CHECKED => write!(f, "checked"),
+            ArithmeticMode::WRAPPING => write!(f, "wrapping"),
+            ArithmeticMode::SATURATING => write!(f, "saturating"),
+        }
+    }
+}
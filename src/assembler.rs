@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+
+use crate::error::VmError;
+use crate::instruction::{Instruction, OperandType};
+
+/// Resolves the labels used by jump instructions to instruction indices and
+/// gives each variable a slot.
+///
+/// The first pass builds a table of every `label` and `func`, the second pass stores the
+/// index of the referenced label in each jump's `target`, so no label lookups
+/// happen while the program runs. Variables are numbered from 0 in the order
+/// they first appear, and the slot is stored in the `target` of each `load`
+/// and `store`.
+pub fn assemble(mut instructions: Vec<Instruction>) -> Result<Vec<Instruction>, VmError> {
+    let mut labels: HashMap<&str, usize> = HashMap::new();
+    for (p, i) in instructions.iter().enumerate() {
+        if i.instruction_type.defines_label() && labels.insert(&i.label, p).is_some() {
+            return Err(VmError::DuplicateLabel { label: i.label.clone(), location: i.location.clone() });
+        }
+    }
+    let mut variables: HashMap<&str, usize> = HashMap::new();
+    let mut targets = Vec::with_capacity(instructions.len());
+    for i in &instructions {
+        match i.instruction_type.operand_type() {
+            OperandType::VARIABLE => {
+                let next = variables.len();
+                targets.push(Some(*variables.entry(&i.label).or_insert(next)));
+                continue;
+            },
+            OperandType::SLOT => {
+                targets.push(i.target);
+                continue;
+            },
+            _ => {}
+        }
+        if !i.instruction_type.is_jump() {
+            targets.push(None);
+            continue;
+        }
+        match labels.get(i.label.as_str()) {
+            Some(&target) => targets.push(Some(target)),
+            None => return Err(VmError::UndefinedLabel { label: i.label.clone(), location: i.location.clone() }),
+        }
+    }
+    for (i, target) in instructions.iter_mut().zip(targets) {
+        i.target = target;
+    }
+    Ok(instructions)
+}
+
+/// Number of variable slots used by assembled instructions.
+pub fn variable_count(instructions: &[Instruction]) -> usize {
+    instructions.iter()
+        .filter(|i| i.instruction_type.operand_type() == OperandType::VARIABLE)
+        .filter_map(|i| i.target)
+        .max()
+        .map_or(0, |slot| slot + 1)
+}
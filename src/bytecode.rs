@@ -0,0 +1,279 @@
+use std::rc::Rc;
+
+use crate::error::{Location, VmError};
+use crate::instruction::{Instruction, InstructionType, OperandType, Signature, REGISTER_COUNT};
+use crate::program::narrow;
+use crate::value::{identical, Value};
+
+/// Magic bytes at the start of every `.hsb` file.
+pub const MAGIC: &[u8; 4] = b"HSB\0";
+pub const VERSION: u16 = 2;
+
+const TAG_INT: u8 = 0;
+const TAG_FLOAT: u8 = 1;
+const TAG_BOOL: u8 = 2;
+const TAG_LONG: u8 = 3;
+const TAG_DOUBLE: u8 = 4;
+const TAG_STRING: u8 = 5;
+
+/// Returns true if `bytes` starts with the `.hsb` magic header.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Encodes assembled instructions into the `.hsb` format.
+///
+/// The layout is, with all integers little endian:
+///
+/// ```text
+/// magic "HSB\0", version: u16
+/// constant count: u32, constants: (tag: u8, payload)*
+/// name count: u32, names: (length: u32, utf-8 bytes)*
+/// function count: u32, functions: (name: u32, args: u32, locals: u32)*
+/// instruction count: u32, instructions: (opcode: u8, operand: u32)*
+/// ```
+///
+/// Longs and doubles have an 8 byte payload and strings are stored like names,
+/// as a length followed by UTF-8 bytes. The other constants have 4 bytes. The
+/// operand of a push is an index into the constant pool, the operand of a
+/// `label`, `load` or `store` is an index into the name table and the operand
+/// of a jump is the resolved instruction index of its target. A `func` refers
+/// to an entry in the function table and `lload` and `lstore` hold their slot
+/// number. Register instructions hold the destination register in the low
+/// byte and the source in the next. Variable slots are assigned again when
+/// the program is loaded.
+pub fn encode(instructions: &[Instruction]) -> Result<Vec<u8>, VmError> {
+    let mut constants: Vec<Value> = Vec::new();
+    let mut names: Vec<&str> = Vec::new();
+    let mut functions: Vec<(u32, u32, u32)> = Vec::new();
+    let mut code: Vec<(u8, u32)> = Vec::with_capacity(instructions.len());
+    for i in instructions {
+        let opcode = InstructionType::ALL.iter().position(|&t| t == i.instruction_type).unwrap() as u8;
+        let operand = match i.instruction_type.operand_type() {
+            OperandType::NONE => 0,
+            OperandType::VALUE(_) => {
+                let value = i.value.clone().ok_or_else(|| VmError::MissingArgument { instruction: i.instruction_type.mnemonic().to_string(), location: i.location.clone() })?;
+                index_of(&mut constants, value, identical)
+            },
+            OperandType::LABEL if i.instruction_type == InstructionType::LABEL => index_of(&mut names, i.label.as_str(), |a, b| a == b),
+            OperandType::VARIABLE => index_of(&mut names, i.label.as_str(), |a, b| a == b),
+            OperandType::LABEL => {
+                let target = i.target.ok_or_else(|| VmError::UndefinedLabel { label: i.label.clone(), location: i.location.clone() })?;
+                narrow(target, i)?
+            },
+            OperandType::SLOT => narrow(i.target.unwrap_or_default(), i)?,
+            OperandType::REGISTERS(_) => u32::from(i.registers[0]) | u32::from(i.registers[1]) << 8,
+            OperandType::FUNCTION => {
+                let signature = i.signature.unwrap_or_default();
+                let name = index_of(&mut names, i.label.as_str(), |a, b| a == b);
+                functions.push((name, narrow(signature.args, i)?, narrow(signature.locals, i)?));
+                (functions.len() - 1) as u32
+            },
+        };
+        code.push((opcode, operand));
+    }
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(constants.len() as u32).to_le_bytes());
+    for c in &constants {
+        match c {
+            Value::INT(v) => {
+                bytes.push(TAG_INT);
+                bytes.extend_from_slice(&v.to_le_bytes());
+            },
+            Value::FLOAT(v) => {
+                bytes.push(TAG_FLOAT);
+                bytes.extend_from_slice(&v.to_le_bytes());
+            },
+            Value::BOOL(v) => {
+                bytes.push(TAG_BOOL);
+                bytes.extend_from_slice(&(*v as u32).to_le_bytes());
+            },
+            Value::LONG(v) => {
+                bytes.push(TAG_LONG);
+                bytes.extend_from_slice(&v.to_le_bytes());
+            },
+            Value::DOUBLE(v) => {
+                bytes.push(TAG_DOUBLE);
+                bytes.extend_from_slice(&v.to_le_bytes());
+            },
+            Value::STRING(v) => {
+                bytes.push(TAG_STRING);
+                bytes.extend_from_slice(&(v.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(v.as_bytes());
+            },
+            Value::ARRAY(_) => unreachable!("arrays have no literal form"),
+        }
+    }
+    bytes.extend_from_slice(&(names.len() as u32).to_le_bytes());
+    for name in &names {
+        bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(name.as_bytes());
+    }
+    bytes.extend_from_slice(&(functions.len() as u32).to_le_bytes());
+    for (name, args, locals) in &functions {
+        bytes.extend_from_slice(&name.to_le_bytes());
+        bytes.extend_from_slice(&args.to_le_bytes());
+        bytes.extend_from_slice(&locals.to_le_bytes());
+    }
+    bytes.extend_from_slice(&(code.len() as u32).to_le_bytes());
+    for (opcode, operand) in code {
+        bytes.push(opcode);
+        bytes.extend_from_slice(&operand.to_le_bytes());
+    }
+    Ok(bytes)
+}
+
+/// Decodes a `.hsb` file back into assembled instructions. `file` is recorded
+/// in the location of each instruction; bytecode carries no line numbers.
+pub fn decode(bytes: &[u8], file: Option<&str>) -> Result<Vec<Instruction>, VmError> {
+    let location = Location::new(file.map(Rc::from), 0, 0);
+    let mut reader = Reader { bytes, offset: 0, location: &location };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(reader.error("missing HSB header"));
+    }
+    let version = u16::from_le_bytes(reader.array()?);
+    if version != VERSION {
+        return Err(reader.error(&format!("unsupported version {}", version)));
+    }
+
+    let mut constants = Vec::new();
+    for _ in 0..reader.u32()? {
+        let tag = reader.take(1)?[0];
+        match tag {
+            TAG_INT => constants.push(Value::INT(i32::from_le_bytes(reader.array()?))),
+            TAG_FLOAT => constants.push(Value::FLOAT(f32::from_le_bytes(reader.array()?))),
+            TAG_BOOL => constants.push(Value::BOOL(reader.u32()? != 0)),
+            TAG_LONG => constants.push(Value::LONG(i64::from_le_bytes(reader.array()?))),
+            TAG_DOUBLE => constants.push(Value::DOUBLE(f64::from_le_bytes(reader.array()?))),
+            TAG_STRING => constants.push(Value::STRING(Rc::from(reader.str("string constant")?))),
+            _ => return Err(reader.error(&format!("unknown constant tag {}", tag))),
+        }
+    }
+    let mut names = Vec::new();
+    for _ in 0..reader.u32()? {
+        names.push(reader.str("label name")?.to_string());
+    }
+    let mut functions = Vec::new();
+    for _ in 0..reader.u32()? {
+        let name = reader.u32()? as usize;
+        let name = names.get(name).ok_or_else(|| reader.error(&format!("name {} out of range", name)))?;
+        let signature = Signature { args: reader.u32()? as usize, locals: reader.u32()? as usize };
+        functions.push((name.as_str(), signature));
+    }
+
+    // Each instruction takes five bytes, so a count larger than the rest of
+    // the file is not trusted with the allocation.
+    let count = reader.u32()? as usize;
+    let mut instructions = Vec::with_capacity(count.min((bytes.len() - reader.offset) / 5));
+    for _ in 0..count {
+        let opcode = reader.take(1)?[0];
+        let operand = reader.u32()? as usize;
+        let instruction_type = *InstructionType::ALL.get(opcode as usize).ok_or_else(|| reader.error(&format!("unknown opcode {}", opcode)))?;
+        let mut instruction = match instruction_type.operand_type() {
+            OperandType::NONE => Instruction::new(instruction_type),
+            OperandType::VALUE(value_type) => {
+                let value = constants.get(operand).cloned().ok_or_else(|| reader.error(&format!("constant {} out of range", operand)))?;
+                if value.value_type() != value_type {
+                    return Err(reader.error(&format!("constant {} is not a {:?}", operand, value_type)));
+                }
+                Instruction::with_value(instruction_type, value)
+            },
+            OperandType::LABEL | OperandType::VARIABLE if !instruction_type.is_jump() => {
+                let name = names.get(operand).ok_or_else(|| reader.error(&format!("name {} out of range", operand)))?;
+                Instruction::with_label(instruction_type, name)
+            },
+            OperandType::SLOT => Instruction::with_slot(instruction_type, operand),
+            OperandType::REGISTERS(count) => {
+                let registers = [operand as u8, (operand >> 8) as u8];
+                if operand >> 16 != 0 || registers[..count].iter().any(|&r| usize::from(r) >= REGISTER_COUNT) {
+                    return Err(reader.error(&format!("invalid registers {:#x}", operand)));
+                }
+                Instruction::with_registers(instruction_type, registers)
+            },
+            OperandType::FUNCTION => {
+                let &(name, signature) = functions.get(operand).ok_or_else(|| reader.error(&format!("function {} out of range", operand)))?;
+                Instruction::with_signature(instruction_type, name, signature)
+            },
+            OperandType::LABEL | OperandType::VARIABLE => {
+                let mut jump = Instruction::new(instruction_type);
+                jump.target = Some(operand);
+                jump
+            },
+        };
+        instruction.location = location.clone();
+        instructions.push(instruction);
+    }
+    if reader.offset != bytes.len() {
+        return Err(reader.error("trailing bytes after last instruction"));
+    }
+
+    // Give jumps the name of the label they point at so they can be
+    // disassembled back into source.
+    for p in 0..instructions.len() {
+        let Some(target) = instructions[p].target.filter(|_| instructions[p].instruction_type.is_jump()) else { continue };
+        match instructions.get(target) {
+            Some(label) if label.instruction_type.defines_label() => instructions[p].label = label.label.clone(),
+            _ => return Err(reader.error(&format!("jump target {} is not a label", target))),
+        }
+    }
+    Ok(instructions)
+}
+
+/// Turns instructions back into `.hsc` source, one instruction per line.
+pub fn disassemble(instructions: &[Instruction]) -> String {
+    let mut source = String::new();
+    for i in instructions {
+        source.push_str(&i.to_string());
+        source.push('\n');
+    }
+    source
+}
+
+// Returns the index of `value` in `pool`, adding it if it is not there yet.
+fn index_of<T>(pool: &mut Vec<T>, value: T, eq: fn(&T, &T) -> bool) -> u32 {
+    match pool.iter().position(|v| eq(v, &value)) {
+        Some(index) => index as u32,
+        None => {
+            pool.push(value);
+            (pool.len() - 1) as u32
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    location: &'a Location,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], VmError> {
+        let end = self.offset.checked_add(length).filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| self.error("unexpected end of file"))?;
+        let slice = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(slice)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], VmError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> Result<u32, VmError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    // Reads a length followed by that many bytes of UTF-8. `what` names the
+    // string in the error if the bytes are not valid.
+    fn str(&mut self, what: &str) -> Result<&'a str, VmError> {
+        let length = self.u32()? as usize;
+        std::str::from_utf8(self.take(length)?).map_err(|_| self.error(&format!("{} is not UTF-8", what)))
+    }
+
+    fn error(&self, message: &str) -> VmError {
+        VmError::BadBytecode { message: format!("{} at byte {}", message, self.offset), location: self.location.clone() }
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashSet;
+use std::io::{self, BufRead, Write};
+
+use crate::error::VmError;
+use crate::machine::StackMachine;
+use crate::value::format_list;
+
+/// Runs a loaded program one command at a time. Before each instruction it
+/// stops, shows the instruction pointer, the source line and the stack, and
+/// reads a command:
+///
+/// - `step` executes one instruction.
+/// - `next` executes one instruction, running a `call` through to its return.
+/// - `continue` runs until a breakpoint, the watched stack depth or the end.
+/// - `break <label|line>` stops before a label, or the first instruction on
+///   or after a source line.
+/// - `watch <depth>` stops once the stack grows to at least `depth` values;
+///   `watch` on its own clears it.
+/// - `print stack` shows the stack again.
+/// - `quit` stops debugging, as does the end of the commands.
+///
+/// The time limit only counts time spent running, not waiting for commands.
+pub struct Debugger<'a> {
+    sm: &'a mut StackMachine,
+    source: Option<&'a str>,
+    breakpoints: HashSet<usize>,
+    watch: Option<usize>,
+}
+
+impl<'a> Debugger<'a> {
+    /// Debugs the program loaded into `sm`. `source` is the text it was
+    /// parsed from, if any, and is used to show source lines.
+    pub fn new(sm: &'a mut StackMachine, source: Option<&'a str>) -> Debugger<'a> {
+        Debugger { sm, source, breakpoints: HashSet::new(), watch: None }
+    }
+
+    /// Reads commands from `commands` and writes what the debugger shows to
+    /// `out` until the program finishes or the commands run out. Errors from
+    /// the program end the session.
+    pub fn run(&mut self, commands: &mut dyn BufRead, out: &mut dyn Write) -> Result<(), VmError> {
+        if !self.sm.is_halted() {
+            self.show(out).map_err(|e| self.io_error(e))?;
+        }
+        let mut line = String::new();
+        while !self.sm.is_halted() {
+            self.sm.pause_clock();
+            line.clear();
+            if commands.read_line(&mut line).map_err(|e| self.io_error(e))? == 0 {
+                break;
+            }
+            match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [] => continue,
+                ["step"] => self.resume(out, |_| true)?,
+                ["next"] => {
+                    let depth = self.sm.call_depth();
+                    self.resume(out, |sm| sm.call_depth() <= depth)?
+                },
+                ["continue"] => self.resume(out, |_| false)?,
+                ["break", place] => self.set_breakpoint(out, place).map_err(|e| self.io_error(e))?,
+                ["watch"] => self.watch = None,
+                ["watch", depth] => match depth.parse() {
+                    Ok(depth) => self.watch = Some(depth),
+                    Err(_) => writeln!(out, "Bad stack depth '{}'", depth).map_err(|e| self.io_error(e))?,
+                },
+                ["print", "stack"] => self.show_stack(out).map_err(|e| self.io_error(e))?,
+                ["quit"] => break,
+                _ => writeln!(out, "Unknown command '{}'", line.trim()).map_err(|e| self.io_error(e))?,
+            }
+        }
+        if self.sm.is_halted() {
+            writeln!(out, "Program finished").and_then(|_| self.show_stack(out)).map_err(|e| self.io_error(e))?;
+        }
+        self.sm.flush()
+    }
+
+    // Executes instructions until `until` holds, the program finishes or the
+    // next instruction has a breakpoint or follows the stack growing to the
+    // watched depth, then shows where it stopped. At least one instruction is
+    // always executed.
+    fn resume(&mut self, out: &mut dyn Write, until: impl Fn(&StackMachine) -> bool) -> Result<(), VmError> {
+        loop {
+            let depth = self.sm.stack().len();
+            self.sm.step()?;
+            if self.sm.is_halted() {
+                return Ok(());
+            }
+            let stop = if until(self.sm) {
+                Some(String::new())
+            } else if self.breakpoints.contains(&self.sm.pointer()) {
+                Some(String::from("Breakpoint\n"))
+            } else {
+                self.watch.filter(|&watch| depth < watch && self.sm.stack().len() >= watch)
+                    .map(|watch| format!("Stack depth reached {}\n", watch))
+            };
+            if let Some(reason) = stop {
+                return write!(out, "{}", reason).and_then(|_| self.show(out)).map_err(|e| self.io_error(e));
+            }
+        }
+    }
+
+    fn set_breakpoint(&mut self, out: &mut dyn Write, place: &str) -> io::Result<()> {
+        let instructions = self.sm.instructions();
+        let index = match place.parse::<usize>() {
+            Ok(line) => instructions.iter().position(|i| i.location.line >= line),
+            Err(_) => instructions.iter().position(|i| i.instruction_type.defines_label() && i.label == place),
+        };
+        match index {
+            Some(index) => {
+                self.breakpoints.insert(index);
+                writeln!(out, "Breakpoint at ip {}", index)
+            },
+            None => writeln!(out, "No label or line '{}'", place),
+        }
+    }
+
+    // Shows the next instruction, as written in the source when it is known,
+    // followed by the stack.
+    fn show(&self, out: &mut dyn Write) -> io::Result<()> {
+        let pointer = self.sm.pointer();
+        let instruction = &self.sm.instructions()[pointer];
+        let line = instruction.location.line;
+        match self.source.and_then(|s| s.lines().nth(line.wrapping_sub(1))) {
+            Some(text) => writeln!(out, "ip {}, line {}: {}", pointer, line, text.trim())?,
+            None => writeln!(out, "ip {}: {}", pointer, instruction)?,
+        }
+        self.show_stack(out)
+    }
+
+    fn show_stack(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "stack: {}", format_list(self.sm.stack()))
+    }
+
+    fn io_error(&self, error: io::Error) -> VmError {
+        let location = self.sm.instructions().get(self.sm.pointer()).map(|i| i.location.clone()).unwrap_or_default();
+        VmError::IoError { message: error.to_string(), location }
+    }
+}
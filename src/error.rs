@@ -0,0 +1,212 @@
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::value::ValueType;
+
+pub(crate) static DIFFERENT_TYPES: &str = "Cannot compare different value types!";
+pub(crate) static STACK_UNDERFLOW: &str = "Stack underflow";
+pub(crate) static TOP_IS_NOT_INTEGER: &str = "Top of stack is not type of integer";
+pub(crate) static TOP_IS_NOT_FLOAT: &str = "Top of stack is not type of float";
+pub(crate) static TOP_IS_NOT_BOOL: &str = "Top of stack is not type of bool";
+pub(crate) static TOP_IS_NOT_LONG: &str = "Top of stack is not type of long";
+pub(crate) static TOP_IS_NOT_DOUBLE: &str = "Top of stack is not type of double";
+pub(crate) static TOP_IS_NOT_STRING: &str = "Top of stack is not type of string";
+pub(crate) static TOP_IS_NOT_ARRAY: &str = "Top of stack is not type of array";
+pub(crate) static TOP_IS_NOT_NUMBER: &str = "Top of stack is not a number";
+pub(crate) static REGISTER_TYPES: &str = "Registers do not hold values of the same type";
+
+// Message for a value that is not of the type an instruction expects.
+pub(crate) fn top_is_not(value_type: ValueType) -> &'static str {
+    match value_type {
+        ValueType::INT => TOP_IS_NOT_INTEGER,
+        ValueType::FLOAT => TOP_IS_NOT_FLOAT,
+        ValueType::BOOL => TOP_IS_NOT_BOOL,
+        ValueType::LONG => TOP_IS_NOT_LONG,
+        ValueType::DOUBLE => TOP_IS_NOT_DOUBLE,
+        ValueType::STRING => TOP_IS_NOT_STRING,
+        ValueType::ARRAY => TOP_IS_NOT_ARRAY,
+    }
+}
+
+// Number of active calls listed in a diagnostic before the rest are elided.
+const MAX_TRACE_NOTES: usize = 10;
+
+/// Position of an instruction in its source file. Lines and columns start at
+/// 1; a line of 0 means the position is unknown.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Location {
+    pub file: Option<Rc<str>>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    pub fn new(file: Option<Rc<str>>, line: usize, column: usize) -> Location {
+        Location { file, line, column }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let file = self.file.as_deref().unwrap_or("<source>");
+        if self.line == 0 {
+            write!(f, "{}", file)
+        } else {
+            write!(f, "{}:{}:{}", file, self.line, self.column)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VmError {
+    StackUnderflow { location: Location },
+    TypeMismatch { message: &'static str, location: Location },
+    DivideByZero { location: Location },
+    IntegerOverflow { location: Location },
+    ShiftOutOfRange { amount: i64, location: Location },
+    UnknownInstruction { name: String, location: Location },
+    UnknownRegister { name: String, location: Location },
+    MissingArgument { instruction: String, location: Location },
+    UnexpectedArgument { argument: String, location: Location },
+    UndefinedLabel { label: String, location: Location },
+    DuplicateLabel { label: String, location: Location },
+    BadLiteral { literal: String, location: Location },
+    UninitialisedVariable { name: String, location: Location },
+    InvalidCharacter { value: i32, location: Location },
+    IndexOutOfRange { index: i32, length: usize, location: Location },
+    NegativeLength { length: i32, location: Location },
+    ReferenceCycle { location: Location },
+    OutOfMemory { bytes: usize, location: Location },
+    CallStackOverflow { depth: usize, location: Location },
+    StackOverflow { size: usize, location: Location },
+    StepLimitExceeded { limit: u64, location: Location },
+    TimeLimitExceeded { limit: Duration, location: Location },
+    AllocationLimitExceeded { limit: usize, location: Location },
+    ReturnWithoutCall { location: Location },
+    NoFunctionFrame { location: Location },
+    BadBytecode { message: String, location: Location },
+    IoError { message: String, location: Location },
+    /// An error raised inside one or more calls, with the location of each
+    /// active `call` instruction, innermost first.
+    Traced { error: Box<VmError>, trace: Vec<Location> },
+}
+
+impl VmError {
+    pub fn location(&self) -> &Location {
+        match self {
+            VmError::StackUnderflow { location }
+            | VmError::TypeMismatch { location, .. }
+            | VmError::DivideByZero { location }
+            | VmError::IntegerOverflow { location }
+            | VmError::ShiftOutOfRange { location, .. }
+            | VmError::UnknownInstruction { location, .. }
+            | VmError::UnknownRegister { location, .. }
+            | VmError::MissingArgument { location, .. }
+            | VmError::UnexpectedArgument { location, .. }
+            | VmError::UndefinedLabel { location, .. }
+            | VmError::DuplicateLabel { location, .. }
+            | VmError::BadLiteral { location, .. }
+            | VmError::UninitialisedVariable { location, .. }
+            | VmError::InvalidCharacter { location, .. }
+            | VmError::IndexOutOfRange { location, .. }
+            | VmError::NegativeLength { location, .. }
+            | VmError::ReferenceCycle { location }
+            | VmError::OutOfMemory { location, .. }
+            | VmError::CallStackOverflow { location, .. }
+            | VmError::StackOverflow { location, .. }
+            | VmError::StepLimitExceeded { location, .. }
+            | VmError::TimeLimitExceeded { location, .. }
+            | VmError::AllocationLimitExceeded { location, .. }
+            | VmError::ReturnWithoutCall { location }
+            | VmError::NoFunctionFrame { location }
+            | VmError::BadBytecode { location, .. }
+            | VmError::IoError { location, .. } => location,
+            VmError::Traced { error, .. } => error.location(),
+        }
+    }
+
+    /// The underlying error, without any call trace.
+    pub fn kind(&self) -> &VmError {
+        match self {
+            VmError::Traced { error, .. } => error.kind(),
+            _ => self,
+        }
+    }
+
+    /// Exit status for a runner that stops on this error. Each resource limit
+    /// has its own status so a supervisor can tell which one was hit; every
+    /// other error is 1.
+    pub fn exit_code(&self) -> i32 {
+        match self.kind() {
+            VmError::StepLimitExceeded { .. } => 3,
+            VmError::StackOverflow { .. } => 4,
+            VmError::CallStackOverflow { .. } => 5,
+            VmError::TimeLimitExceeded { .. } => 6,
+            VmError::AllocationLimitExceeded { .. } => 7,
+            _ => 1,
+        }
+    }
+
+    /// Formats the error the way a compiler would, quoting the offending line
+    /// from `source` when it is available.
+    pub fn diagnostic(&self, source: Option<&str>) -> String {
+        let location = self.location();
+        let line = source.and_then(|s| s.lines().nth(location.line.wrapping_sub(1)));
+        let number = location.line.to_string();
+        let gutter = " ".repeat(if line.is_some() { number.len() } else { 1 });
+        let mut out = format!("error: {}\n{}--> {}\n", self, gutter, location);
+        if let Some(line) = line {
+            out.push_str(&format!("{} |\n", gutter));
+            out.push_str(&format!("{} | {}\n", number, line.trim_end_matches('\r')));
+            out.push_str(&format!("{} | {}^\n", gutter, " ".repeat(location.column.saturating_sub(1))));
+        }
+        if let VmError::Traced { trace, .. } = self {
+            for call in trace.iter().take(MAX_TRACE_NOTES) {
+                out.push_str(&format!("{} = note: called from {}\n", gutter, call));
+            }
+            if trace.len() > MAX_TRACE_NOTES {
+                out.push_str(&format!("{} = note: ... and {} more calls\n", gutter, trace.len() - MAX_TRACE_NOTES));
+            }
+        }
+        out
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::StackUnderflow { .. } => write!(f, "{}", STACK_UNDERFLOW),
+            VmError::TypeMismatch { message, .. } => write!(f, "{}", message),
+            VmError::DivideByZero { .. } => write!(f, "Division by zero"),
+            VmError::IntegerOverflow { .. } => write!(f, "Integer overflow"),
+            VmError::ShiftOutOfRange { amount, .. } => write!(f, "Shift amount {} is out of range 0 to 31", amount),
+            VmError::UnknownInstruction { name, .. } => write!(f, "Instruction '{}' not implemented", name),
+            VmError::UnknownRegister { name, .. } => write!(f, "Unknown register '{}', expected r0 to r15", name),
+            VmError::MissingArgument { instruction, .. } => write!(f, "'{}' argument missing", instruction),
+            VmError::UnexpectedArgument { argument, .. } => write!(f, "Unexpected argument '{}'", argument),
+            VmError::UndefinedLabel { label, .. } => write!(f, "Label '{}' is not found", label),
+            VmError::DuplicateLabel { label, .. } => write!(f, "Label '{}' is already defined", label),
+            VmError::BadLiteral { literal, .. } => write!(f, "Invalid literal '{}'", literal),
+            VmError::UninitialisedVariable { name, .. } => write!(f, "Variable '{}' is loaded before it is stored", name),
+            VmError::InvalidCharacter { value, .. } => write!(f, "'{}' is not a valid character", value),
+            VmError::IndexOutOfRange { index, length, .. } => write!(f, "Index {} is out of range for length {}", index, length),
+            VmError::NegativeLength { length, .. } => write!(f, "Array length {} is negative", length),
+            VmError::ReferenceCycle { .. } => write!(f, "Cannot store an array inside itself"),
+            VmError::OutOfMemory { bytes, .. } => write!(f, "Out of memory allocating {} bytes", bytes),
+            VmError::CallStackOverflow { depth, .. } => write!(f, "Call stack overflow, more than {} nested calls", depth),
+            VmError::StackOverflow { size, .. } => write!(f, "Stack overflow, more than {} values on the stack", size),
+            VmError::StepLimitExceeded { limit, .. } => write!(f, "Step limit exceeded, more than {} instructions executed", limit),
+            VmError::TimeLimitExceeded { limit, .. } => write!(f, "Time limit exceeded, ran for more than {:?}", limit),
+            VmError::AllocationLimitExceeded { limit, .. } => write!(f, "Allocation limit exceeded, more than {} bytes allocated", limit),
+            VmError::ReturnWithoutCall { .. } => write!(f, "Return without call"),
+            VmError::NoFunctionFrame { .. } => write!(f, "Local variable used outside a function"),
+            VmError::BadBytecode { message, .. } => write!(f, "Invalid bytecode: {}", message),
+            VmError::IoError { message, .. } => write!(f, "{}", message),
+            VmError::Traced { error, .. } => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for VmError {}
@@ -0,0 +1,396 @@
+use std::fmt;
+
+use crate::error::Location;
+use crate::value::{Value, ValueType};
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum InstructionType {
+    PUSHINT,    // Push value onto stack.
+    PUSHFLOAT,  // Push value onto stack.
+    POP,        // Pop value off top of stack.
+    ADDI,       // Pop two off stack then add them and put onto stack.
+    SUBI,       // Pop two off stack then substract them and put onto stack.
+    MULI,       // Pop two off stack then multiply them and put onto stack.
+    DIVI,       // Pop two off stack then divide them and put onto stack.
+    ADDF,       // Pop two off stack then add them and put onto stack.
+    SUBF,       // Pop two off stack then substract them and put onto stack.
+    MULF,       // Pop two off stack then multiply them and put onto stack.
+    DIVF,       // Pop two off stack then divide them and put onto stack.
+    PRINT,      // Print value on top of stack.
+    PRINTLINE,  // Print line of value on top of stack.
+    PRINTASCII, // Print ASCII character on top of stack.
+    INPUTINT,   // Read user input and push value on stack as int.
+    INPUTFLOAT, // Read user input and push value on stack as int.
+    LABEL,      // Label for jumps to change the instruction pointer to.
+    J,          // Jump to label
+    JE,         // Jump to label if equal
+    JN,         // Jump to label if not equal
+    JL,         // Jump to label if less than
+    JG,         // Jump to label if greater than
+    JLE,        // Jump to label if less than or equal
+    JGE,        // Jump to label if greter than or equal
+    TOINT,      // Pop off stack and convert to int32
+    TOFLOAT,    // Pop off stack and convert to float32
+    DUPLICATE,  // Push a copy of the top of stack
+    CALL,       // Push return address onto return stack and jump to label
+    RET,        // Pop return address off return stack and jump to it
+    SWAP,       // Swap the top two values: a b -> b a
+    OVER,       // Copy the second value to the top: a b -> a b a
+    ROT,        // Rotate the third value to the top: a b c -> b c a
+    DROP,       // Pop value off top of stack, same as POP
+    NIP,        // Remove the second value: a b -> b
+    TUCK,       // Copy the top value below the second: a b -> b a b
+    PICK,       // Pop n and copy the nth value (0 is the top) to the top
+    ROLL,       // Pop n and move the nth value (0 is the top) to the top
+    MODI,       // Pop two off stack then put the remainder of dividing them onto stack.
+    NEGI,       // Pop off stack then negate it and put onto stack.
+    ABSI,       // Pop off stack then put its absolute value onto stack.
+    ANDI,       // Pop two off stack then put their bitwise and onto stack.
+    ORI,        // Pop two off stack then put their bitwise or onto stack.
+    XORI,       // Pop two off stack then put their bitwise exclusive or onto stack.
+    NOTI,       // Pop off stack then put its bitwise complement onto stack.
+    SHLI,       // Pop two off stack then shift the first left by the second.
+    SHRI,       // Pop two off stack then shift the first right by the second, keeping the sign.
+    USHRI,      // Pop two off stack then shift the first right by the second, filling with zeros.
+    EQ,         // Pop two off stack then put true onto stack if equal.
+    NE,         // Pop two off stack then put true onto stack if not equal.
+    LT,         // Pop two off stack then put true onto stack if less than.
+    GT,         // Pop two off stack then put true onto stack if greater than.
+    LE,         // Pop two off stack then put true onto stack if less than or equal.
+    GE,         // Pop two off stack then put true onto stack if greater than or equal.
+    JZ,         // Pop off stack and jump to label if false or zero
+    JNZ,        // Pop off stack and jump to label if true or not zero
+    PUSHBOOL,   // Push value onto stack.
+    AND,        // Pop two bools off stack then put true onto stack if both are true.
+    OR,         // Pop two bools off stack then put true onto stack if either is true.
+    NOT,        // Pop bool off stack then put its negation onto stack.
+    PUSHLONG,   // Push value onto stack.
+    PUSHDOUBLE, // Push value onto stack.
+    ADDL,       // Pop two off stack then add them and put onto stack.
+    SUBL,       // Pop two off stack then substract them and put onto stack.
+    MULL,       // Pop two off stack then multiply them and put onto stack.
+    DIVL,       // Pop two off stack then divide them and put onto stack.
+    MODL,       // Pop two off stack then put the remainder of dividing them onto stack.
+    NEGL,       // Pop off stack then negate it and put onto stack.
+    ABSL,       // Pop off stack then put its absolute value onto stack.
+    ADDD,       // Pop two off stack then add them and put onto stack.
+    SUBD,       // Pop two off stack then substract them and put onto stack.
+    MULD,       // Pop two off stack then multiply them and put onto stack.
+    DIVD,       // Pop two off stack then divide them and put onto stack.
+    TOLONG,     // Pop off stack and convert to int64
+    TODOUBLE,   // Pop off stack and convert to float64
+    PUSHSTRING, // Push value onto stack.
+    CONCAT,     // Pop two strings off stack then join them and put onto stack.
+    LEN,        // Pop string off stack then put its length in characters onto stack.
+    SUBSTR,     // Pop string, start and length off stack then put the substring onto stack.
+    CHARAT,     // Pop string and index off stack then put the character code at the index onto stack.
+    TOSTR,      // Pop off stack and convert to string
+    PARSEI,     // Pop string off stack and parse it as int
+    PARSEF,     // Pop string off stack and parse it as float
+    NEWARR,     // Pop length off stack then put a new array of that many zeros onto stack.
+    ALOAD,      // Pop array and index off stack then put the element at the index onto stack.
+    ASTORE,     // Pop array, index and value off stack then store the value at the index.
+    ALEN,       // Pop array off stack then put its length onto stack.
+    STORE,      // Pop off stack and store in variable
+    LOAD,       // Put the value of variable onto stack.
+    FUNC,       // Used as location for calls that set up a frame with arguments and locals.
+    LLOAD,      // Put the value of local slot of current function onto stack.
+    LSTORE,     // Pop off stack and store in local slot of current function
+    MOV,        // Copy second register into first register.
+    LDR,        // Put the value of register onto stack.
+    STR,        // Pop off stack and store in register
+    ADDR,       // Add second register to first register.
+    SUBR,       // Substract second register from first register.
+    MULR,       // Multiply first register by second register.
+    DIVR,       // Divide first register by second register.
+    MODR,       // Put the remainder of dividing first register by second register into first register.
+}
+
+/// Number of general purpose registers, `r0` to `r15`.
+pub const REGISTER_COUNT: usize = 16;
+
+/// Kind of argument an instruction takes in source.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum OperandType {
+    NONE,
+    VALUE(ValueType),
+    LABEL,
+    VARIABLE,
+    /// A local slot number.
+    SLOT,
+    /// A function name followed by its number of arguments and locals.
+    FUNCTION,
+    /// The given number of registers, such as `r0`.
+    REGISTERS(usize),
+}
+
+impl OperandType {
+    /// Number of arguments written after the instruction name.
+    pub fn argument_count(self) -> usize {
+        match self {
+            OperandType::NONE => 0,
+            OperandType::VALUE(_) | OperandType::LABEL | OperandType::VARIABLE | OperandType::SLOT => 1,
+            OperandType::FUNCTION => 3,
+            OperandType::REGISTERS(count) => count,
+        }
+    }
+}
+
+impl InstructionType {
+    /// Every instruction type. The position in this list is the opcode used by
+    /// the `.hsb` bytecode format, so new instructions must be added at the end.
+    pub const ALL: [InstructionType; 99] = [
+        InstructionType::PUSHINT, InstructionType::PUSHFLOAT, InstructionType::POP,
+        InstructionType::ADDI, InstructionType::SUBI, InstructionType::MULI, InstructionType::DIVI,
+        InstructionType::ADDF, InstructionType::SUBF, InstructionType::MULF, InstructionType::DIVF,
+        InstructionType::PRINT, InstructionType::PRINTLINE, InstructionType::PRINTASCII,
+        InstructionType::INPUTINT, InstructionType::INPUTFLOAT, InstructionType::LABEL,
+        InstructionType::J, InstructionType::JE, InstructionType::JN, InstructionType::JL,
+        InstructionType::JG, InstructionType::JLE, InstructionType::JGE,
+        InstructionType::TOINT, InstructionType::TOFLOAT, InstructionType::DUPLICATE,
+        InstructionType::CALL, InstructionType::RET,
+        InstructionType::SWAP, InstructionType::OVER, InstructionType::ROT, InstructionType::DROP,
+        InstructionType::NIP, InstructionType::TUCK, InstructionType::PICK, InstructionType::ROLL,
+        InstructionType::MODI, InstructionType::NEGI, InstructionType::ABSI,
+        InstructionType::ANDI, InstructionType::ORI, InstructionType::XORI, InstructionType::NOTI,
+        InstructionType::SHLI, InstructionType::SHRI, InstructionType::USHRI,
+        InstructionType::EQ, InstructionType::NE, InstructionType::LT, InstructionType::GT,
+        InstructionType::LE, InstructionType::GE, InstructionType::JZ, InstructionType::JNZ,
+        InstructionType::PUSHBOOL, InstructionType::AND, InstructionType::OR, InstructionType::NOT,
+        InstructionType::PUSHLONG, InstructionType::PUSHDOUBLE,
+        InstructionType::ADDL, InstructionType::SUBL, InstructionType::MULL, InstructionType::DIVL,
+        InstructionType::MODL, InstructionType::NEGL, InstructionType::ABSL,
+        InstructionType::ADDD, InstructionType::SUBD, InstructionType::MULD, InstructionType::DIVD,
+        InstructionType::TOLONG, InstructionType::TODOUBLE,
+        InstructionType::PUSHSTRING, InstructionType::CONCAT, InstructionType::LEN, InstructionType::SUBSTR,
+        InstructionType::CHARAT, InstructionType::TOSTR, InstructionType::PARSEI, InstructionType::PARSEF,
+        InstructionType::NEWARR, InstructionType::ALOAD, InstructionType::ASTORE, InstructionType::ALEN,
+        InstructionType::STORE, InstructionType::LOAD, InstructionType::FUNC, InstructionType::LLOAD, InstructionType::LSTORE,
+        InstructionType::MOV, InstructionType::LDR, InstructionType::STR,
+        InstructionType::ADDR, InstructionType::SUBR, InstructionType::MULR, InstructionType::DIVR, InstructionType::MODR,
+    ];
+
+    /// Name of the instruction in `.hsc` source.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            InstructionType::PUSHINT => "pushi",
+            InstructionType::PUSHFLOAT => "pushf",
+            InstructionType::POP => "pop",
+            InstructionType::ADDI => "addi",
+            InstructionType::SUBI => "subi",
+            InstructionType::MULI => "muli",
+            InstructionType::DIVI => "divi",
+            InstructionType::ADDF => "addf",
+            InstructionType::SUBF => "subf",
+            InstructionType::MULF => "mulf",
+            InstructionType::DIVF => "divf",
+            InstructionType::PRINT => "print",
+            InstructionType::PRINTLINE => "printl",
+            InstructionType::PRINTASCII => "printa",
+            InstructionType::INPUTINT => "inputi",
+            InstructionType::INPUTFLOAT => "inputf",
+            InstructionType::LABEL => "label",
+            InstructionType::J => "j",
+            InstructionType::JE => "je",
+            InstructionType::JN => "jn",
+            InstructionType::JL => "jl",
+            InstructionType::JG => "jg",
+            InstructionType::JLE => "jle",
+            InstructionType::JGE => "jge",
+            InstructionType::TOINT => "toi",
+            InstructionType::TOFLOAT => "tof",
+            InstructionType::DUPLICATE => "dup",
+            InstructionType::CALL => "call",
+            InstructionType::RET => "ret",
+            InstructionType::SWAP => "swap",
+            InstructionType::OVER => "over",
+            InstructionType::ROT => "rot",
+            InstructionType::DROP => "drop",
+            InstructionType::NIP => "nip",
+            InstructionType::TUCK => "tuck",
+            InstructionType::PICK => "pick",
+            InstructionType::ROLL => "roll",
+            InstructionType::MODI => "modi",
+            InstructionType::NEGI => "negi",
+            InstructionType::ABSI => "absi",
+            InstructionType::ANDI => "andi",
+            InstructionType::ORI => "ori",
+            InstructionType::XORI => "xori",
+            InstructionType::NOTI => "noti",
+            InstructionType::SHLI => "shli",
+            InstructionType::SHRI => "shri",
+            InstructionType::USHRI => "ushri",
+            InstructionType::EQ => "eq",
+            InstructionType::NE => "ne",
+            InstructionType::LT => "lt",
+            InstructionType::GT => "gt",
+            InstructionType::LE => "le",
+            InstructionType::GE => "ge",
+            InstructionType::JZ => "jz",
+            InstructionType::JNZ => "jnz",
+            InstructionType::PUSHBOOL => "pushb",
+            InstructionType::AND => "and",
+            InstructionType::OR => "or",
+            InstructionType::NOT => "not",
+            InstructionType::PUSHLONG => "pushl",
+            InstructionType::PUSHDOUBLE => "pushd",
+            InstructionType::ADDL => "addl",
+            InstructionType::SUBL => "subl",
+            InstructionType::MULL => "mull",
+            InstructionType::DIVL => "divl",
+            InstructionType::MODL => "modl",
+            InstructionType::NEGL => "negl",
+            InstructionType::ABSL => "absl",
+            InstructionType::ADDD => "addd",
+            InstructionType::SUBD => "subd",
+            InstructionType::MULD => "muld",
+            InstructionType::DIVD => "divd",
+            InstructionType::TOLONG => "tol",
+            InstructionType::TODOUBLE => "tod",
+            InstructionType::PUSHSTRING => "pushs",
+            InstructionType::CONCAT => "concat",
+            InstructionType::LEN => "len",
+            InstructionType::SUBSTR => "substr",
+            InstructionType::CHARAT => "charat",
+            InstructionType::TOSTR => "tostr",
+            InstructionType::PARSEI => "parsei",
+            InstructionType::PARSEF => "parsef",
+            InstructionType::NEWARR => "newarr",
+            InstructionType::ALOAD => "aload",
+            InstructionType::ASTORE => "astore",
+            InstructionType::ALEN => "alen",
+            InstructionType::STORE => "store",
+            InstructionType::LOAD => "load",
+            InstructionType::FUNC => "func",
+            InstructionType::LLOAD => "lload",
+            InstructionType::LSTORE => "lstore",
+            InstructionType::MOV => "mov",
+            InstructionType::LDR => "ldr",
+            InstructionType::STR => "str",
+            InstructionType::ADDR => "addr",
+            InstructionType::SUBR => "subr",
+            InstructionType::MULR => "mulr",
+            InstructionType::DIVR => "divr",
+            InstructionType::MODR => "modr",
+        }
+    }
+
+    pub fn from_mnemonic(name: &str) -> Option<InstructionType> {
+        InstructionType::ALL.iter().copied().find(|t| t.mnemonic() == name)
+    }
+
+    pub fn operand_type(self) -> OperandType {
+        match self {
+            InstructionType::PUSHINT | InstructionType::PUSHFLOAT | InstructionType::PUSHBOOL | InstructionType::PUSHLONG
+            | InstructionType::PUSHDOUBLE | InstructionType::PUSHSTRING => OperandType::VALUE(self.value_type().unwrap()),
+            InstructionType::LABEL => OperandType::LABEL,
+            t if t.is_jump() => OperandType::LABEL,
+            InstructionType::STORE | InstructionType::LOAD => OperandType::VARIABLE,
+            InstructionType::LLOAD | InstructionType::LSTORE => OperandType::SLOT,
+            InstructionType::FUNC => OperandType::FUNCTION,
+            InstructionType::LDR | InstructionType::STR => OperandType::REGISTERS(1),
+            InstructionType::MOV | InstructionType::ADDR | InstructionType::SUBR | InstructionType::MULR | InstructionType::DIVR
+            | InstructionType::MODR => OperandType::REGISTERS(2),
+            _ => OperandType::NONE,
+        }
+    }
+
+    /// Type of the values a typed instruction pushes or works on. Untyped
+    /// instructions such as `dup` or the comparisons accept any type.
+    pub fn value_type(self) -> Option<ValueType> {
+        match self {
+            InstructionType::PUSHINT | InstructionType::ADDI | InstructionType::SUBI | InstructionType::MULI | InstructionType::DIVI
+            | InstructionType::MODI | InstructionType::NEGI | InstructionType::ABSI | InstructionType::ANDI | InstructionType::ORI
+            | InstructionType::XORI | InstructionType::NOTI | InstructionType::SHLI | InstructionType::SHRI | InstructionType::USHRI
+            | InstructionType::INPUTINT | InstructionType::TOINT => Some(ValueType::INT),
+            InstructionType::PUSHFLOAT | InstructionType::ADDF | InstructionType::SUBF | InstructionType::MULF | InstructionType::DIVF
+            | InstructionType::INPUTFLOAT | InstructionType::TOFLOAT => Some(ValueType::FLOAT),
+            InstructionType::PUSHBOOL | InstructionType::AND | InstructionType::OR | InstructionType::NOT => Some(ValueType::BOOL),
+            InstructionType::PUSHLONG | InstructionType::ADDL | InstructionType::SUBL | InstructionType::MULL | InstructionType::DIVL
+            | InstructionType::MODL | InstructionType::NEGL | InstructionType::ABSL | InstructionType::TOLONG => Some(ValueType::LONG),
+            InstructionType::PUSHDOUBLE | InstructionType::ADDD | InstructionType::SUBD | InstructionType::MULD | InstructionType::DIVD
+            | InstructionType::TODOUBLE => Some(ValueType::DOUBLE),
+            InstructionType::PUSHSTRING | InstructionType::CONCAT => Some(ValueType::STRING),
+            _ => None,
+        }
+    }
+
+    /// Whether the instruction defines a name that jumps and calls can target.
+    pub fn defines_label(self) -> bool {
+        matches!(self, InstructionType::LABEL | InstructionType::FUNC)
+    }
+
+    /// Whether the instruction transfers control to a label, which the
+    /// assembler resolves to an instruction index.
+    pub fn is_jump(self) -> bool {
+        matches!(self, InstructionType::CALL | InstructionType::J | InstructionType::JE | InstructionType::JN | InstructionType::JL
+            | InstructionType::JG | InstructionType::JLE | InstructionType::JGE | InstructionType::JZ | InstructionType::JNZ)
+    }
+}
+
+/// Frame layout declared by `func`. Arguments are the first local slots.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct Signature {
+    pub args: usize,
+    pub locals: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Instruction
+{
+    pub instruction_type: InstructionType,
+    pub value: Option<Value>,
+    /// Name of the label or variable the instruction refers to.
+    pub label: String,
+    /// Instruction index of a jump's label or slot of a variable, filled in
+    /// by the assembler.
+    pub target: Option<usize>,
+    pub signature: Option<Signature>,
+    /// Registers named by a register instruction, destination first.
+    pub registers: [u8; 2],
+    pub location: Location
+}
+
+impl Instruction {
+    pub fn new(instruction_type: InstructionType) -> Instruction {
+        Instruction { instruction_type, value: None, label: String::new(), target: None, signature: None, registers: [0; 2], location: Location::default() }
+    }
+
+    pub fn with_value(instruction_type: InstructionType, value: Value) -> Instruction {
+        Instruction { instruction_type, value: Some(value), label: String::new(), target: None, signature: None, registers: [0; 2], location: Location::default() }
+    }
+
+    pub fn with_label(instruction_type: InstructionType, label: &str) -> Instruction {
+        Instruction { instruction_type, value: None, label: label.to_string(), target: None, signature: None, registers: [0; 2], location: Location::default() }
+    }
+
+    pub fn with_slot(instruction_type: InstructionType, slot: usize) -> Instruction {
+        Instruction { instruction_type, value: None, label: String::new(), target: Some(slot), signature: None, registers: [0; 2], location: Location::default() }
+    }
+
+    pub fn with_registers(instruction_type: InstructionType, registers: [u8; 2]) -> Instruction {
+        Instruction { registers, ..Instruction::new(instruction_type) }
+    }
+
+    pub fn with_signature(instruction_type: InstructionType, name: &str, signature: Signature) -> Instruction {
+        Instruction { signature: Some(signature), ..Instruction::with_label(instruction_type, name) }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.instruction_type.mnemonic())?;
+        match (self.instruction_type.operand_type(), &self.value) {
+            (OperandType::VALUE(_), Some(Value::STRING(text))) => write!(f, " {:?}", text),
+            (OperandType::VALUE(_), Some(value)) => write!(f, " {}", value),
+            (OperandType::LABEL | OperandType::VARIABLE, _) => write!(f, " {}", self.label),
+            (OperandType::SLOT, _) => write!(f, " {}", self.target.unwrap_or_default()),
+            (OperandType::FUNCTION, _) => {
+                let signature = self.signature.unwrap_or_default();
+                write!(f, " {} {} {}", self.label, signature.args, signature.locals)
+            },
+            (OperandType::REGISTERS(count), _) => self.registers[..count].iter().try_for_each(|r| write!(f, " r{}", r)),
+            _ => Ok(()),
+        }
+    }
+}
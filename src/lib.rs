@@ -0,0 +1,42 @@
+//! Huw's Stack Machine.
+//!
+//! The interpreter is split into a parser that turns `.hsc` source into
+//! [`Instruction`]s, an assembler that resolves jump labels and a
+//! [`StackMachine`] that executes them. Assembled programs can be stored in
+//! the binary `.hsb` format described in [`bytecode`]. Output from the
+//! print instructions and input for the input instructions go through the
+//! reader and writer given to the machine, so it can be embedded in other
+//! tools. Every failure is reported as a [`VmError`] carrying the
+//! [`Location`] of the offending instruction. A [`Debugger`] can step through a
+//! loaded program under the control of a stream of commands, and a [`Tracer`]
+//! records each instruction it executes as a line of JSON. A [`Profiler`]
+//! counts and times the instructions a program executes. A [`Repl`] runs
+//! instructions as they are typed.
+
+#![allow(clippy::upper_case_acronyms)]
+
+mod arithmetic;
+mod assembler;
+pub mod bytecode;
+mod debugger;
+mod error;
+mod instruction;
+mod machine;
+mod parser;
+mod profiler;
+mod program;
+mod repl;
+mod tracer;
+mod value;
+
+pub use arithmetic::{ArithmeticMode, BinaryOp, Numeric, UnaryOp};
+pub use assembler::assemble;
+pub use debugger::Debugger;
+pub use error::{Location, VmError};
+pub use instruction::{Instruction, InstructionType, OperandType};
+pub use machine::{StackMachine, DEFAULT_MAX_CALL_DEPTH};
+pub use parser::parse;
+pub use profiler::Profiler;
+pub use repl::Repl;
+pub use tracer::Tracer;
+pub use value::{Array, Value, ValueError, ValueType};
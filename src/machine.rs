@@ -0,0 +1,849 @@
+use std::cmp::Ordering;
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::error::{
+    top_is_not, Location, VmError, DIFFERENT_TYPES, REGISTER_TYPES, TOP_IS_NOT_ARRAY, TOP_IS_NOT_INTEGER, TOP_IS_NOT_NUMBER,
+    TOP_IS_NOT_STRING,
+};
+use crate::arithmetic::{ArithmeticMode, BinaryOp, UnaryOp};
+use crate::assembler;
+use crate::bytecode;
+use crate::instruction::{Instruction, InstructionType, REGISTER_COUNT};
+use crate::parser;
+use crate::program::Program;
+use crate::value::{Array, Value, ValueError, ValueType};
+
+/// Default limit on the number of nested `call`s.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1024;
+
+// Number of instructions executed between checks of the time limit, so the
+// clock is not read on every step.
+const TIME_CHECK_INTERVAL: u64 = 1024;
+
+pub struct StackMachine {
+    instructions: Vec<Instruction>,
+    program: Program,
+    stack: Vec<Value>,
+    globals: Vec<Option<Value>>,
+    pointer: usize,
+    current: usize,
+    registers: [Value; REGISTER_COUNT],
+    frames: Vec<Frame>,
+    max_call_depth: usize,
+    // Instructions executed since the program was loaded, when the clock for
+    // the time limit was last started and how long it ran before it was last
+    // paused. The step and time limits are only checked once `steps` reaches
+    // `check_at`.
+    steps: u64,
+    started: Option<Instant>,
+    elapsed: Duration,
+    check_at: u64,
+    max_steps: u64,
+    max_stack_size: usize,
+    time_limit: Option<Duration>,
+    // Bytes of arrays, strings and function locals allocated since the
+    // program was loaded, counted against an allocation budget. Memory freed
+    // again is not given back.
+    allocated: usize,
+    max_allocated: usize,
+    arithmetic_mode: ArithmeticMode,
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+}
+
+// An active call. Calls to a `func` also own the function's local slots and
+// remember the stack height to unwind to when the function returns.
+struct Frame {
+    return_address: usize,
+    base: Option<usize>,
+    locals: Vec<Option<Value>>,
+}
+
+impl Default for StackMachine {
+    fn default() -> Self {
+        StackMachine::new()
+    }
+}
+
+impl StackMachine {
+    /// Creates a machine that reads from stdin and writes to stdout.
+    pub fn new() -> StackMachine {
+        StackMachine::with_io(Box::new(io::BufReader::new(io::stdin())), Box::new(io::stdout()))
+    }
+
+    /// Creates a machine that reads input instructions from `input` and
+    /// writes print instructions to `output`.
+    pub fn with_io(input: Box<dyn BufRead>, output: Box<dyn Write>) -> StackMachine {
+        StackMachine { instructions: Vec::new(), program: Program::default(), stack: Vec::new(), globals: Vec::new(), pointer: 0,
+            registers: Self::clear_registers(), current: 0,
+            frames: Vec::new(), max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            steps: 0, started: None, elapsed: Duration::ZERO, check_at: 0, max_steps: u64::MAX, max_stack_size: usize::MAX, time_limit: None,
+            allocated: 0, max_allocated: usize::MAX,
+            arithmetic_mode: ArithmeticMode::default(), input, output }
+    }
+
+    /// Parses `source` and replaces the loaded program with it. The stack is
+    /// left untouched and the instruction pointer is reset to the start.
+    pub fn load_source(&mut self, source: &str) -> Result<(), VmError> {
+        self.load(parser::parse(source, None)?)
+    }
+
+    /// Same as `load_source`, but errors report `file` as the source file.
+    pub fn load_named_source(&mut self, file: &str, source: &str) -> Result<(), VmError> {
+        self.load(parser::parse(source, Some(file))?)
+    }
+
+    /// Loads a program in the `.hsb` bytecode format.
+    pub fn load_bytecode(&mut self, file: Option<&str>, bytes: &[u8]) -> Result<(), VmError> {
+        self.load(bytecode::decode(bytes, file)?)
+    }
+
+    /// Assembles `instructions` and replaces the loaded program with them.
+    /// Undefined and duplicate labels are rejected here. All variables are
+    /// cleared, registers are set back to 0 and the step count, time limit
+    /// and allocation count start again.
+    pub fn load(&mut self, instructions: Vec<Instruction>) -> Result<(), VmError> {
+        let instructions = assembler::assemble(instructions)?;
+        self.program = Program::lower(&instructions)?;
+        self.instructions = instructions;
+        self.globals = vec![None; assembler::variable_count(&self.instructions)];
+        self.registers = Self::clear_registers();
+        self.pointer = 0;
+        self.frames.clear();
+        self.restart_limits();
+        Ok(())
+    }
+
+    /// Appends `instructions` to the loaded program and moves the instruction
+    /// pointer to the first of them, so running continues with the new code.
+    /// The stack, variables and registers are kept, and jumps may target
+    /// labels defined by earlier instructions. Calls left active by an error
+    /// are abandoned. The step count, time limit and allocation count start
+    /// again, so each addition runs with the full limits. If the instructions
+    /// are rejected nothing changes.
+    pub fn extend(&mut self, instructions: Vec<Instruction>) -> Result<(), VmError> {
+        let start = self.instructions.len();
+        let mut all = self.instructions.clone();
+        all.extend(instructions);
+        let all = assembler::assemble(all)?;
+        self.program = Program::lower(&all)?;
+        self.globals.resize(assembler::variable_count(&all), None);
+        self.instructions = all;
+        self.pointer = start;
+        self.frames.clear();
+        self.restart_limits();
+        Ok(())
+    }
+
+    /// Unloads the program and empties the stack, variables and registers.
+    /// Limits and the arithmetic mode are kept.
+    pub fn reset(&mut self) {
+        self.stack.clear();
+        self.instructions.clear();
+        self.program = Program::default();
+        self.globals.clear();
+        self.registers = Self::clear_registers();
+        self.pointer = 0;
+        self.frames.clear();
+        self.restart_limits();
+    }
+
+    fn restart_limits(&mut self) {
+        self.steps = 0;
+        self.started = None;
+        self.elapsed = Duration::ZERO;
+        self.check_at = 0;
+        self.allocated = 0;
+    }
+
+    fn clear_registers() -> [Value; REGISTER_COUNT] {
+        std::array::from_fn(|_| Value::INT(0))
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    /// The registers `r0` to `r15`.
+    pub fn registers(&self) -> &[Value] {
+        &self.registers
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    /// Number of calls that have not returned yet.
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Sets how many calls may be nested before `call` fails with
+    /// `VmError::CallStackOverflow`.
+    pub fn set_max_call_depth(&mut self, max_call_depth: usize) {
+        self.max_call_depth = max_call_depth;
+    }
+
+    /// Number of instructions executed since the program was loaded or
+    /// extended.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Sets how many instructions may be executed before `step` fails with
+    /// `VmError::StepLimitExceeded`. There is no limit by default.
+    pub fn set_max_steps(&mut self, max_steps: u64) {
+        self.max_steps = max_steps;
+        self.check_at = self.steps;
+    }
+
+    /// Sets how many values the stack may hold before an instruction that
+    /// grows it further fails with `VmError::StackOverflow`. There is no limit
+    /// by default.
+    pub fn set_max_stack_size(&mut self, max_stack_size: usize) {
+        self.max_stack_size = max_stack_size;
+    }
+
+    /// Sets how long the program may run, timed from its first instruction,
+    /// before `step` fails with `VmError::TimeLimitExceeded`. The clock is
+    /// checked every 1024 instructions and not while waiting for input, so a
+    /// program can overrun by that much.
+    pub fn set_time_limit(&mut self, time_limit: Option<Duration>) {
+        self.time_limit = time_limit;
+        self.check_at = self.steps;
+    }
+
+    /// Stops the clock for the time limit until the next instruction runs, so
+    /// time spent between calls to `step`, such as waiting for a debugger
+    /// command, does not count.
+    pub fn pause_clock(&mut self) {
+        if let Some(started) = self.started.take() {
+            self.elapsed += started.elapsed();
+            self.check_at = self.steps;
+        }
+    }
+
+    /// Sets an allocation budget: how many bytes of arrays, strings and
+    /// function locals may be allocated in total before the instruction
+    /// allocating more fails with `VmError::AllocationLimitExceeded`. This is
+    /// not a limit on memory in use. Every allocation since the program was
+    /// loaded counts, even once it is freed, so a program that allocates in a
+    /// loop uses the budget up like the step limit. There is no limit by
+    /// default.
+    pub fn set_max_allocated(&mut self, max_allocated: usize) {
+        self.max_allocated = max_allocated;
+    }
+
+    pub fn arithmetic_mode(&self) -> ArithmeticMode {
+        self.arithmetic_mode
+    }
+
+    /// Sets how integer instructions handle overflow.
+    pub fn set_arithmetic_mode(&mut self, arithmetic_mode: ArithmeticMode) {
+        self.arithmetic_mode = arithmetic_mode;
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.pointer >= self.program.ops.len()
+    }
+
+    /// Runs the loaded program until the instruction pointer moves past the
+    /// last instruction.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        while self.step()? {}
+        self.flush()
+    }
+
+    /// Flushes anything printed that the output writer is still holding.
+    pub fn flush(&mut self) -> Result<(), VmError> {
+        self.output.flush().map_err(|e| self.io_error(e))
+    }
+
+    /// Executes a single instruction. Returns `false` if the program had
+    /// already finished and nothing was executed. Errors raised inside a call
+    /// carry the locations of the active calls.
+    #[inline]
+    pub fn step(&mut self) -> Result<bool, VmError> {
+        if self.is_halted() {
+            return Ok(false);
+        }
+        match self.check_limits().and_then(|_| self.execute()).and_then(|_| self.check_stack_size()) {
+            Ok(()) => Ok(true),
+            Err(error) if !self.frames.is_empty() => {
+                let trace = self.frames.iter().rev().map(|f| self.instructions[f.return_address - 1].location.clone()).collect();
+                Err(VmError::Traced { error: Box::new(error), trace })
+            },
+            Err(error) => Err(error),
+        }
+    }
+
+    // Counts the instruction about to execute, failing if that would go over
+    // the step limit or the time limit has passed.
+    #[inline]
+    fn check_limits(&mut self) -> Result<(), VmError> {
+        if self.steps >= self.check_at {
+            self.check_step_and_time_limits()?;
+        }
+        self.steps += 1;
+        Ok(())
+    }
+
+    // Checks the step and time limits and works out when they next need
+    // checking: at the step limit, or sooner to look at the clock again.
+    fn check_step_and_time_limits(&mut self) -> Result<(), VmError> {
+        if self.steps >= self.max_steps {
+            return Err(VmError::StepLimitExceeded { limit: self.max_steps, location: self.instructions[self.pointer].location.clone() });
+        }
+        self.check_at = self.max_steps;
+        if let Some(limit) = self.time_limit {
+            let started = *self.started.get_or_insert_with(Instant::now);
+            if self.elapsed + started.elapsed() > limit {
+                return Err(VmError::TimeLimitExceeded { limit, location: self.instructions[self.pointer].location.clone() });
+            }
+            self.check_at = self.check_at.min(self.steps + TIME_CHECK_INTERVAL);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn check_stack_size(&self) -> Result<(), VmError> {
+        if self.stack.len() > self.max_stack_size {
+            return Err(VmError::StackOverflow { size: self.max_stack_size, location: self.location() });
+        }
+        Ok(())
+    }
+
+    // Counts `bytes` about to be allocated, failing instead if that would go
+    // over the allocation budget.
+    fn allocate(&mut self, bytes: usize) -> Result<(), VmError> {
+        let allocated = self.allocated.saturating_add(bytes);
+        if allocated > self.max_allocated {
+            return Err(VmError::AllocationLimitExceeded { limit: self.max_allocated, location: self.location() });
+        }
+        self.allocated = allocated;
+        Ok(())
+    }
+
+    fn execute(&mut self) -> Result<(), VmError> {
+        let current = self.pointer;
+        let instruction_type = self.program.ops[current].instruction_type;
+        self.current = current;
+        self.pointer += 1;
+        match instruction_type {
+            InstructionType::PUSHINT | InstructionType::PUSHFLOAT | InstructionType::PUSHBOOL | InstructionType::PUSHLONG
+            | InstructionType::PUSHDOUBLE | InstructionType::PUSHSTRING => {
+                let value = self.operand();
+                self.stack.push(value);
+            },
+            InstructionType::POP | InstructionType::DROP => {
+                self.pop()?;
+            },
+            InstructionType::ADDI | InstructionType::ADDF | InstructionType::ADDL | InstructionType::ADDD => self.binary(BinaryOp::ADD)?,
+            InstructionType::SUBI | InstructionType::SUBF | InstructionType::SUBL | InstructionType::SUBD => self.binary(BinaryOp::SUB)?,
+            InstructionType::MULI | InstructionType::MULF | InstructionType::MULL | InstructionType::MULD => self.binary(BinaryOp::MUL)?,
+            InstructionType::DIVI | InstructionType::DIVF | InstructionType::DIVL | InstructionType::DIVD => self.binary(BinaryOp::DIV)?,
+            InstructionType::MODI | InstructionType::MODL => self.binary(BinaryOp::REM)?,
+            InstructionType::ANDI | InstructionType::AND => self.binary(BinaryOp::AND)?,
+            InstructionType::ORI | InstructionType::OR => self.binary(BinaryOp::OR)?,
+            InstructionType::XORI => self.binary(BinaryOp::XOR)?,
+            InstructionType::SHLI => self.binary(BinaryOp::SHL)?,
+            InstructionType::SHRI => self.binary(BinaryOp::SHR)?,
+            InstructionType::USHRI => self.binary(BinaryOp::USHR)?,
+            InstructionType::NEGI | InstructionType::NEGL => self.unary(UnaryOp::NEG)?,
+            InstructionType::ABSI | InstructionType::ABSL => self.unary(UnaryOp::ABS)?,
+            InstructionType::NOTI | InstructionType::NOT => self.unary(UnaryOp::NOT)?,
+            InstructionType::TOINT | InstructionType::TOFLOAT | InstructionType::TOLONG | InstructionType::TODOUBLE => self.convert()?,
+            InstructionType::CONCAT => {
+                let bytes = self.stack.iter().rev().take(2).map(|v| if let Value::STRING(text) = v { text.len() } else { 0 }).sum();
+                self.allocate(bytes)?;
+                self.binary(BinaryOp::ADD)?
+            },
+            InstructionType::LEN => {
+                let text = self.pop_string()?;
+                let length = i32::try_from(text.chars().count()).map_err(|_| VmError::IntegerOverflow { location: self.location() })?;
+                self.stack.push(Value::INT(length));
+            },
+            InstructionType::SUBSTR => {
+                let length = self.pop_int()?;
+                let start = self.pop_int()?;
+                let text = self.pop_string()?;
+                let count = text.chars().count();
+                let begin = self.string_index(start, count)?;
+                let end = self.string_index(if length < 0 { length } else { start.saturating_add(length) }, count)?;
+                let substring: String = text.chars().skip(begin).take(end - begin).collect();
+                self.allocate(substring.len())?;
+                self.stack.push(Value::STRING(Rc::from(substring)));
+            },
+            InstructionType::CHARAT => {
+                let index = self.pop_int()?;
+                let text = self.pop_string()?;
+                let character = usize::try_from(index).ok().and_then(|i| text.chars().nth(i))
+                    .ok_or_else(|| VmError::IndexOutOfRange { index, length: text.chars().count(), location: self.location() })?;
+                self.stack.push(Value::INT(character as i32));
+            },
+            InstructionType::TOSTR => {
+                let top = self.pop()?;
+                let text = top.to_string();
+                self.allocate(text.len())?;
+                self.stack.push(Value::STRING(Rc::from(text)));
+            },
+            InstructionType::PARSEI => {
+                let text = self.pop_string()?;
+                let value: i32 = text.trim().parse().map_err(|_| self.bad_literal(&text))?;
+                self.stack.push(Value::INT(value));
+            },
+            InstructionType::NEWARR => {
+                let length = self.pop_int()?;
+                let length = usize::try_from(length).map_err(|_| VmError::NegativeLength { length, location: self.location() })?;
+                let bytes = length.saturating_mul(mem::size_of::<Value>());
+                self.allocate(bytes)?;
+                let array = Array::new(length).ok_or_else(|| VmError::OutOfMemory { bytes, location: self.location() })?;
+                self.stack.push(Value::ARRAY(array));
+            },
+            InstructionType::ALOAD => {
+                let index = self.pop_int()?;
+                let array = self.pop_array()?;
+                let value = usize::try_from(index).ok().and_then(|i| array.get(i))
+                    .ok_or_else(|| VmError::IndexOutOfRange { index, length: array.len(), location: self.location() })?;
+                self.stack.push(value);
+            },
+            InstructionType::ASTORE => {
+                let value = self.pop()?;
+                let index = self.pop_int()?;
+                let array = self.pop_array()?;
+                if matches!(&value, Value::ARRAY(inner) if inner.contains(&array)) {
+                    return Err(VmError::ReferenceCycle { location: self.location() });
+                }
+                if !usize::try_from(index).is_ok_and(|i| array.set(i, value)) {
+                    return Err(VmError::IndexOutOfRange { index, length: array.len(), location: self.location() });
+                }
+            },
+            InstructionType::ALEN => {
+                let array = self.pop_array()?;
+                let length = i32::try_from(array.len()).map_err(|_| VmError::IntegerOverflow { location: self.location() })?;
+                self.stack.push(Value::INT(length));
+            },
+            InstructionType::STORE => {
+                let value = self.pop()?;
+                let slot = self.target();
+                self.globals[slot] = Some(value);
+            },
+            InstructionType::LOAD => {
+                let slot = self.target();
+                let value = self.globals[slot].clone().ok_or_else(|| VmError::UninitialisedVariable {
+                    name: self.instructions[self.current].label.clone(), location: self.location() })?;
+                self.stack.push(value);
+            },
+            InstructionType::MOV => {
+                let [destination, source] = self.register_operands();
+                self.registers[destination] = self.registers[source].clone();
+            },
+            InstructionType::LDR => {
+                let [register, _] = self.register_operands();
+                self.stack.push(self.registers[register].clone());
+            },
+            InstructionType::STR => {
+                let [register, _] = self.register_operands();
+                self.registers[register] = self.pop()?;
+            },
+            InstructionType::ADDR => self.register_binary(BinaryOp::ADD)?,
+            InstructionType::SUBR => self.register_binary(BinaryOp::SUB)?,
+            InstructionType::MULR => self.register_binary(BinaryOp::MUL)?,
+            InstructionType::DIVR => self.register_binary(BinaryOp::DIV)?,
+            InstructionType::MODR => self.register_binary(BinaryOp::REM)?,
+            InstructionType::PARSEF => {
+                let text = self.pop_string()?;
+                let value: f32 = text.trim().parse().map_err(|_| self.bad_literal(&text))?;
+                self.stack.push(Value::FLOAT(value));
+            },
+            InstructionType::PRINT => {
+                let top = self.peek()?;
+                self.write_value(top, "")?;
+            },
+            InstructionType::PRINTLINE => {
+                let top = self.peek()?;
+                self.write_value(top, "\n")?;
+            },
+            InstructionType::PRINTASCII => {
+                let Value::INT(value) = self.peek()? else {
+                    return Err(self.type_mismatch(TOP_IS_NOT_INTEGER));
+                };
+                let character = u32::try_from(value).ok().and_then(char::from_u32)
+                    .ok_or_else(|| VmError::InvalidCharacter { value, location: self.location() })?;
+                write!(self.output, "{}", character).map_err(|e| self.io_error(e))?;
+            },
+            InstructionType::INPUTINT => {
+                let line = self.read_line()?;
+                let value: i32 = line.trim().parse().map_err(|_| self.bad_literal(&line))?;
+                self.stack.push(Value::INT(value));
+            },
+            InstructionType::INPUTFLOAT => {
+                let line = self.read_line()?;
+                let value: f32 = line.trim().parse().map_err(|_| self.bad_literal(&line))?;
+                self.stack.push(Value::FLOAT(value));
+            }
+            InstructionType::LABEL => { }
+            InstructionType::J => {
+                self.pointer = self.target();
+            },
+            InstructionType::JE => {
+                self.jump_if(|o| o == Some(Ordering::Equal))?;
+            },
+            InstructionType::JN => {
+                self.jump_if(|o| o != Some(Ordering::Equal))?;
+            },
+            InstructionType::JL => {
+                self.jump_if(|o| o == Some(Ordering::Less))?;
+            },
+            InstructionType::JG => {
+                self.jump_if(|o| o == Some(Ordering::Greater))?;
+            },
+            InstructionType::JLE => {
+                self.jump_if(|o| matches!(o, Some(Ordering::Less | Ordering::Equal)))?;
+            },
+            InstructionType::JGE => {
+                self.jump_if(|o| matches!(o, Some(Ordering::Greater | Ordering::Equal)))?;
+            },
+            InstructionType::JZ => {
+                self.jump_if_zero(true)?;
+            },
+            InstructionType::JNZ => {
+                self.jump_if_zero(false)?;
+            },
+            InstructionType::EQ => {
+                self.push_comparison(|o| o == Some(Ordering::Equal))?;
+            },
+            InstructionType::NE => {
+                self.push_comparison(|o| o != Some(Ordering::Equal))?;
+            },
+            InstructionType::LT => {
+                self.push_comparison(|o| o == Some(Ordering::Less))?;
+            },
+            InstructionType::GT => {
+                self.push_comparison(|o| o == Some(Ordering::Greater))?;
+            },
+            InstructionType::LE => {
+                self.push_comparison(|o| matches!(o, Some(Ordering::Less | Ordering::Equal)))?;
+            },
+            InstructionType::GE => {
+                self.push_comparison(|o| matches!(o, Some(Ordering::Greater | Ordering::Equal)))?;
+            },
+            InstructionType::CALL => {
+                if self.frames.len() >= self.max_call_depth {
+                    return Err(VmError::CallStackOverflow { depth: self.max_call_depth, location: self.location() });
+                }
+                let target = self.target();
+                let called = self.program.ops[target];
+                let signature = (called.instruction_type == InstructionType::FUNC).then(|| self.program.signatures[called.operand as usize]);
+                let frame = match signature {
+                    Some(signature) => {
+                        let len = self.require(signature.args)?;
+                        let slots = signature.args.checked_add(signature.locals);
+                        let bytes = slots.unwrap_or(usize::MAX).saturating_mul(mem::size_of::<Option<Value>>());
+                        self.allocate(bytes)?;
+                        let mut locals: Vec<Option<Value>> = Vec::new();
+                        if slots.is_none_or(|slots| locals.try_reserve_exact(slots).is_err()) {
+                            return Err(VmError::OutOfMemory { bytes, location: self.location() });
+                        }
+                        locals.extend(self.stack.drain(len - signature.args..).map(Some));
+                        locals.resize(signature.args + signature.locals, None);
+                        Frame { return_address: self.pointer, base: Some(self.stack.len()), locals }
+                    },
+                    None => Frame { return_address: self.pointer, base: None, locals: Vec::new() },
+                };
+                self.frames.push(frame);
+                self.pointer = target;
+            },
+            InstructionType::RET => {
+                let frame = self.frames.pop().ok_or_else(|| VmError::ReturnWithoutCall { location: self.location() })?;
+                // Leave only the return value, if the function pushed one, on
+                // top of the stack it was called with.
+                if let Some(base) = frame.base {
+                    if self.stack.len() > base {
+                        let result = self.stack.pop();
+                        self.stack.truncate(base);
+                        self.stack.extend(result);
+                    }
+                }
+                self.pointer = frame.return_address;
+            },
+            InstructionType::FUNC => { }
+            InstructionType::LLOAD => {
+                let (frame, slot) = self.local()?;
+                let value = self.frames[frame].locals[slot].clone()
+                    .ok_or_else(|| VmError::UninitialisedVariable { name: format!("local {}", slot), location: self.location() })?;
+                self.stack.push(value);
+            },
+            InstructionType::LSTORE => {
+                let (frame, slot) = self.local()?;
+                let value = self.pop()?;
+                self.frames[frame].locals[slot] = Some(value);
+            },
+            InstructionType::DUPLICATE => {
+                let top = self.peek()?;
+                self.stack.push(top);
+            }
+            InstructionType::SWAP => {
+                let len = self.require(2)?;
+                self.stack.swap(len - 1, len - 2);
+            },
+            InstructionType::OVER => {
+                let len = self.require(2)?;
+                self.stack.push(self.stack[len - 2].clone());
+            },
+            InstructionType::ROT => {
+                let len = self.require(3)?;
+                self.stack[len - 3..].rotate_left(1);
+            },
+            InstructionType::NIP => {
+                let len = self.require(2)?;
+                self.stack.remove(len - 2);
+            },
+            InstructionType::TUCK => {
+                let len = self.require(2)?;
+                self.stack.insert(len - 2, self.stack[len - 1].clone());
+            },
+            InstructionType::PICK => {
+                let depth = self.pop_depth()?;
+                self.stack.push(self.stack[self.stack.len() - 1 - depth].clone());
+            },
+            InstructionType::ROLL => {
+                let depth = self.pop_depth()?;
+                let value = self.stack.remove(self.stack.len() - 1 - depth);
+                self.stack.push(value);
+            }
+        }
+        Ok(())
+    }
+
+    // Jump target or variable slot resolved by the assembler for the current
+    // instruction.
+    fn target(&self) -> usize {
+        self.program.ops[self.current].operand as usize
+    }
+
+    // Location of the instruction currently being executed.
+    fn location(&self) -> Location {
+        self.instructions.get(self.current).map(|i| i.location.clone()).unwrap_or_default()
+    }
+
+    // Value pushed by the current instruction.
+    fn operand(&self) -> Value {
+        self.program.constants[self.program.ops[self.current].operand as usize].clone()
+    }
+
+    fn type_mismatch(&self, message: &'static str) -> VmError {
+        VmError::TypeMismatch { message, location: self.location() }
+    }
+
+    fn bad_literal(&self, literal: &str) -> VmError {
+        VmError::BadLiteral { literal: literal.trim().to_string(), location: self.location() }
+    }
+
+    fn io_error(&self, error: io::Error) -> VmError {
+        VmError::IoError { message: error.to_string(), location: self.location() }
+    }
+
+    fn pop(&mut self) -> Result<Value, VmError> {
+        self.stack.pop().ok_or_else(|| VmError::StackUnderflow { location: self.location() })
+    }
+
+    fn peek(&self) -> Result<Value, VmError> {
+        self.stack.last().cloned().ok_or_else(|| VmError::StackUnderflow { location: self.location() })
+    }
+
+    // Checks the stack holds at least `depth` values and returns its length.
+    fn require(&self, depth: usize) -> Result<usize, VmError> {
+        if self.stack.len() < depth {
+            return Err(VmError::StackUnderflow { location: self.location() });
+        }
+        Ok(self.stack.len())
+    }
+
+    // Pops the integer operand of `pick` and `roll`, checking that it refers
+    // to a value on the remaining stack where 0 is the top.
+    fn pop_depth(&mut self) -> Result<usize, VmError> {
+        let index = self.pop_int()?;
+        match usize::try_from(index) {
+            Ok(depth) if depth < self.stack.len() => Ok(depth),
+            _ => Err(VmError::IndexOutOfRange { index, length: self.stack.len(), location: self.location() }),
+        }
+    }
+
+    fn pop_int(&mut self) -> Result<i32, VmError> {
+        match self.pop()? {
+            Value::INT(value) => Ok(value),
+            _ => Err(self.type_mismatch(TOP_IS_NOT_INTEGER)),
+        }
+    }
+
+    fn pop_string(&mut self) -> Result<Rc<str>, VmError> {
+        match self.pop()? {
+            Value::STRING(text) => Ok(text),
+            _ => Err(self.type_mismatch(TOP_IS_NOT_STRING)),
+        }
+    }
+
+    fn pop_array(&mut self) -> Result<Array, VmError> {
+        match self.pop()? {
+            Value::ARRAY(array) => Ok(array),
+            _ => Err(self.type_mismatch(TOP_IS_NOT_ARRAY)),
+        }
+    }
+
+    // Checks that `index` is a position in a string of `length` characters,
+    // where `length` itself is the position after the last character.
+    fn string_index(&self, index: i32, length: usize) -> Result<usize, VmError> {
+        match usize::try_from(index) {
+            Ok(position) if position <= length => Ok(position),
+            _ => Err(VmError::IndexOutOfRange { index, length, location: self.location() }),
+        }
+    }
+
+    // Finds the innermost function frame and checks the slot of the current
+    // `lload` or `lstore` against it, returning both. Plain calls made from a
+    // function share its locals.
+    fn local(&self) -> Result<(usize, usize), VmError> {
+        let slot = self.target();
+        let frame = self.frames.iter().rposition(|f| f.base.is_some())
+            .ok_or_else(|| VmError::NoFunctionFrame { location: self.location() })?;
+        let length = self.frames[frame].locals.len();
+        if slot >= length {
+            return Err(VmError::IndexOutOfRange { index: i32::try_from(slot).unwrap_or(i32::MAX), length, location: self.location() });
+        }
+        Ok((frame, slot))
+    }
+
+    // Type of the values the current instruction works on.
+    fn value_type(&self) -> ValueType {
+        self.program.ops[self.current].instruction_type.value_type().expect("instruction is typed")
+    }
+
+    // Turns an error from an operation on values into a `VmError`, using
+    // `mismatch` as the message for a type mismatch.
+    fn value_error(&self, error: ValueError, mismatch: &'static str) -> VmError {
+        let location = self.location();
+        match error {
+            ValueError::TypeMismatch => VmError::TypeMismatch { message: mismatch, location },
+            ValueError::DivideByZero => VmError::DivideByZero { location },
+            ValueError::Overflow => VmError::IntegerOverflow { location },
+            ValueError::ShiftOutOfRange(amount) => VmError::ShiftOutOfRange { amount, location },
+        }
+    }
+
+    // Applies `op` to the two registers of the current instruction and stores
+    // the result in the first.
+    fn register_binary(&mut self, op: BinaryOp) -> Result<(), VmError> {
+        let [destination, source] = self.register_operands();
+        let first = self.registers[destination].clone();
+        let second = self.registers[source].clone();
+        if let (BinaryOp::ADD, Value::STRING(a), Value::STRING(b)) = (op, &first, &second) {
+            self.allocate(a.len() + b.len())?;
+        }
+        self.registers[destination] = first.binary(op, second, self.arithmetic_mode).map_err(|e| self.value_error(e, REGISTER_TYPES))?;
+        Ok(())
+    }
+
+    fn register_operands(&self) -> [usize; 2] {
+        let operand = self.program.ops[self.current].operand;
+        [operand as u8, (operand >> 8) as u8].map(usize::from)
+    }
+
+    // Pops two values of the current instruction's type and pushes the result
+    // of `op` on them.
+    fn binary(&mut self, op: BinaryOp) -> Result<(), VmError> {
+        let value_type = self.value_type();
+        let second = self.pop()?;
+        let first = self.pop()?;
+        if first.value_type() != value_type || second.value_type() != value_type {
+            return Err(self.type_mismatch(top_is_not(value_type)));
+        }
+        let result = first.binary(op, second, self.arithmetic_mode).map_err(|e| self.value_error(e, top_is_not(value_type)))?;
+        self.stack.push(result);
+        Ok(())
+    }
+
+    fn unary(&mut self, op: UnaryOp) -> Result<(), VmError> {
+        let value_type = self.value_type();
+        let top = self.pop()?;
+        if top.value_type() != value_type {
+            return Err(self.type_mismatch(top_is_not(value_type)));
+        }
+        let result = top.unary(op, self.arithmetic_mode).map_err(|e| self.value_error(e, top_is_not(value_type)))?;
+        self.stack.push(result);
+        Ok(())
+    }
+
+    // Pops a number and pushes it converted to the current instruction's type.
+    fn convert(&mut self) -> Result<(), VmError> {
+        let top = self.pop()?;
+        if matches!(top.value_type(), ValueType::BOOL | ValueType::STRING | ValueType::ARRAY) {
+            return Err(self.type_mismatch(TOP_IS_NOT_NUMBER));
+        }
+        let result = top.convert(self.value_type(), self.arithmetic_mode).map_err(|e| self.value_error(e, TOP_IS_NOT_NUMBER))?;
+        self.stack.push(result);
+        Ok(())
+    }
+
+    fn write_value(&mut self, value: Value, end: &str) -> Result<(), VmError> {
+        write!(self.output, "{}{}", value, end).map_err(|e| self.io_error(e))
+    }
+
+    // Pops two values of the same type and checks `holds` against their
+    // ordering, which is `None` when a float is NaN. Returns the first value
+    // along with the result.
+    fn compare(&mut self, holds: fn(Option<Ordering>) -> bool) -> Result<(Value, bool), VmError> {
+        let second = self.pop()?;
+        let first = self.pop()?;
+        if second.value_type() != first.value_type() {
+            return Err(self.type_mismatch(DIFFERENT_TYPES));
+        }
+        let holds = holds(first.partial_cmp(&second));
+        Ok((first, holds))
+    }
+
+    // Pops two values, jumps to the current instruction's label if the
+    // comparison holds and pushes the first value back.
+    fn jump_if(&mut self, holds: fn(Option<Ordering>) -> bool) -> Result<(), VmError> {
+        let (first, jump) = self.compare(holds)?;
+        if jump {
+            self.pointer = self.target();
+        }
+        self.stack.push(first);
+        Ok(())
+    }
+
+    // Pops two values and pushes whether the comparison holds.
+    fn push_comparison(&mut self, holds: fn(Option<Ordering>) -> bool) -> Result<(), VmError> {
+        let (_, result) = self.compare(holds)?;
+        self.stack.push(Value::BOOL(result));
+        Ok(())
+    }
+
+    // Pops a value and jumps to the current instruction's label if whether it
+    // is zero matches `zero`. False counts as zero.
+    fn jump_if_zero(&mut self, zero: bool) -> Result<(), VmError> {
+        let top = self.pop()?;
+        if top.is_zero() == zero {
+            self.pointer = self.target();
+        }
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> Result<String, VmError> {
+        self.output.flush().map_err(|e| self.io_error(e))?;
+        let mut line = String::new();
+        match self.input.read_line(&mut line) {
+            Ok(0) => Err(VmError::IoError { message: String::from("Unexpected end of input"), location: self.location() }),
+            Ok(_) => Ok(line),
+            Err(e) => Err(self.io_error(e)),
+        }
+    }
+}
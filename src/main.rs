@@ -1,457 +1,273 @@
 use std::env;
-use std::fs;
-use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::process;
+use std::time::Duration;
 
-#[derive(PartialEq, Eq)]
-enum InstructionType {
-    PUSHINT,    // Push value onto stack.
-    PUSHFLOAT,  // Push value onto stack.
-    POP,        // Pop value off top of stack.
-    ADDI,       // Pop two off stack then add them and put onto stack.
-    SUBI,       // Pop two off stack then substract them and put onto stack.
-    MULI,       // Pop two off stack then multiply them and put onto stack.
-    DIVI,       // Pop two off stack then divide them and put onto stack.
-    ADDF,       // Pop two off stack then add them and put onto stack.
-    SUBF,       // Pop two off stack then substract them and put onto stack.
-    MULF,       // Pop two off stack then multiply them and put onto stack.
-    DIVF,       // Pop two off stack then divide them and put onto stack.
-    PRINT,      // Print value on top of stack.
-    PRINTLINE,  // Print line of value on top of stack.
-    PRINTASCII, // Print ASCII character on top of stack.
-    INPUTINT,   // Read user input and push value on stack as int.
-    INPUTFLOAT, // Read user input and push value on stack as int.
-    LABEL,      // Label for jumps to change the instruction pointer to.
-    J,          // Jump to label
-    JE,         // Jump to label if equal
-    JN,         // Jump to label if not equal
-    JL,         // Jump to label if less than
-    JG,         // Jump to label if greater than
-    JLE,        // Jump to label if less than or equal
-    JGE,        // Jump to label if greter than or equal
-    TOINT,      // Pop off stack and convert to int32
-    TOFLOAT,     // Pop off stack and convert to float32
-    DUPLICATE,  // Pop off stack and push two copies onto stack
+use huw_stack_machine::{assemble, bytecode, parse, ArithmeticMode, Debugger, Profiler, Repl, StackMachine, Tracer, VmError};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("assemble") if args.len() == 4 => assemble_file(&args[2], &args[3]),
+        Some("disassemble") if args.len() == 3 => disassemble_file(&args[2]),
+        Some("debug") => debug_file(&parse_run_options(&args[2..])),
+        _ => {
+            let options = parse_run_options(args.get(1..).unwrap_or_default());
+            match &options.file_path {
+                Some(file_path) => run_file(file_path, &options),
+                None => repl(&options),
+            }
+        },
+    }
 }
 
-#[derive(PartialEq, Eq, Clone, Copy)]
-enum ValueType {
-    INT,
-    FLOAT
+// Options for running a program. Flags may come before or after the file.
+// Without a file the REPL starts.
+struct RunOptions {
+    file_path: Option<String>,
+    arithmetic_mode: ArithmeticMode,
+    trace: Option<TraceOptions>,
+    profile: bool,
+    profile_folded: Option<String>,
+    limits: Limits,
 }
 
-#[derive(Clone, Copy)]
-struct Value {
-    int_value: i32,
-    float_value: f32,
-    value_type: ValueType,
+// Resource limits for running untrusted programs. `None` leaves the
+// machine's default in place.
+#[derive(Default)]
+struct Limits {
+    max_steps: Option<u64>,
+    max_stack_size: Option<usize>,
+    max_call_depth: Option<usize>,
+    time_limit: Option<Duration>,
+    max_allocated: Option<usize>,
 }
 
-struct StackMachine {
-    instructions: Vec<Box<Instruction>>,
-    stack: Vec<Value>
+// Where to write an execution trace and which labels bound the traced code.
+struct TraceOptions {
+    file_path: String,
+    from: Option<String>,
+    to: Option<String>,
 }
 
-struct Instruction
-{
-    instruction_type: InstructionType,
-    value: Option<Value>,
-    label: String
+fn parse_run_options(args: &[String]) -> RunOptions {
+    let mut file_path = None;
+    let mut arithmetic_mode = ArithmeticMode::default();
+    let mut trace_file = None;
+    let mut trace_from = None;
+    let mut trace_to = None;
+    let mut profile = false;
+    let mut profile_folded = None;
+    let mut limits = Limits::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--arithmetic" => {
+                let mode = args.next().unwrap_or_else(|| usage_error("--arithmetic needs a mode"));
+                arithmetic_mode = mode.parse().unwrap_or_else(|e: String| usage_error(&e));
+            },
+            "--trace" => trace_file = Some(args.next().unwrap_or_else(|| usage_error("--trace needs a file")).clone()),
+            "--trace-from" => trace_from = Some(args.next().unwrap_or_else(|| usage_error("--trace-from needs a label")).clone()),
+            "--trace-to" => trace_to = Some(args.next().unwrap_or_else(|| usage_error("--trace-to needs a label")).clone()),
+            "--profile" => profile = true,
+            "--profile-folded" => profile_folded = Some(args.next().unwrap_or_else(|| usage_error("--profile-folded needs a file")).clone()),
+            "--max-steps" => limits.max_steps = Some(number(args.next(), "--max-steps")),
+            "--max-stack" => limits.max_stack_size = Some(number(args.next(), "--max-stack")),
+            "--max-call-depth" => limits.max_call_depth = Some(number(args.next(), "--max-call-depth")),
+            "--max-allocated" => limits.max_allocated = Some(number(args.next(), "--max-allocated")),
+            "--time-limit" => {
+                let seconds: f64 = number(args.next(), "--time-limit");
+                let time_limit = Duration::try_from_secs_f64(seconds).unwrap_or_else(|_| usage_error("--time-limit needs a number of seconds"));
+                limits.time_limit = Some(time_limit);
+            },
+            flag if flag.starts_with("--") => usage_error(&format!("Unknown option '{}'", flag)),
+            _ if file_path.is_some() => usage_error(&format!("Unexpected argument '{}'", arg)),
+            _ => file_path = Some(arg.clone()),
+        }
+    }
+    let trace = match trace_file {
+        Some(file_path) => Some(TraceOptions { file_path, from: trace_from, to: trace_to }),
+        None if trace_from.is_some() || trace_to.is_some() => usage_error("--trace-from and --trace-to need --trace"),
+        None => None,
+    };
+    if trace.is_some() && (profile || profile_folded.is_some()) {
+        usage_error("--trace cannot be used with --profile");
+    }
+    if file_path.is_none() && (trace.is_some() || profile || profile_folded.is_some()) {
+        usage_error("--trace and --profile need a file");
+    }
+    RunOptions { file_path, arithmetic_mode, trace, profile, profile_folded, limits }
 }
 
-impl StackMachine {
-    fn get_pointer(&self, label: &String) -> usize {
-        let mut p : usize = 0;
-        for i in &self.instructions {
-            if i.label == *label && i.instruction_type == InstructionType::LABEL
-            {
-                return p;
-            }
-            p += 1;
+// Reads the number given after `flag`.
+fn number<T: std::str::FromStr>(arg: Option<&String>, flag: &str) -> T {
+    let arg = arg.unwrap_or_else(|| usage_error(&format!("{} needs a number", flag)));
+    arg.parse().unwrap_or_else(|_| usage_error(&format!("Invalid number '{}' for {}", arg, flag)))
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    eprintln!("usage: huw-stack-machine [--arithmetic checked|wrapping|saturating]");
+    eprintln!("                         [--trace TRACE [--trace-from LABEL] [--trace-to LABEL]]");
+    eprintln!("                         [--profile] [--profile-folded FOLDED]");
+    eprintln!("                         [--max-steps N] [--max-stack N] [--max-call-depth N] [--time-limit SECONDS]");
+    eprintln!("                         [--max-allocated BYTES] [FILE]");
+    eprintln!("       huw-stack-machine debug [--arithmetic checked|wrapping|saturating] FILE");
+    eprintln!("       huw-stack-machine assemble FILE.hsc FILE.hsb");
+    eprintln!("       huw-stack-machine disassemble FILE.hsb");
+    process::exit(1);
+}
+
+fn read_file(file_path: &str) -> Vec<u8> {
+    match fs::read(file_path) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("error: Cannot read file '{}': {}", file_path, e);
+            process::exit(1);
         }
-        panic!("Label '{}' is not found", *label);
     }
 }
 
-static DIFFERENT_TYPES: &str = "Cannot compare different value types!";
-static STACK_UNDERFLOW: &str = "Stack underflow";
-static TOP_IS_NOT_INTEGER: &str = "Top of stack is not type of integer";
-static TOP_IS_NOT_FLOAT: &str = "Top of stack is not type of float";
+fn fail(error: VmError, source: Option<&str>) -> ! {
+    eprint!("{}", error.diagnostic(source));
+    process::exit(error.exit_code());
+}
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let mut sm: StackMachine = StackMachine { instructions: Vec::new(), stack: Vec::new() };
-    let file_path: &String = &args[1];
-    println!("Welcome to Huw's Stack Machine");
-    let content = fs::read_to_string(file_path).expect("Cannot read file");
-    let lines = content.split("\n");
-    for line in lines {
-        if line == "" {
-            continue;
+// Applies the arithmetic mode and resource limits given on the command line.
+fn configure(sm: &mut StackMachine, options: &RunOptions) {
+    sm.set_arithmetic_mode(options.arithmetic_mode);
+    let limits = &options.limits;
+    if let Some(max_steps) = limits.max_steps {
+        sm.set_max_steps(max_steps);
+    }
+    if let Some(max_stack_size) = limits.max_stack_size {
+        sm.set_max_stack_size(max_stack_size);
+    }
+    if let Some(max_call_depth) = limits.max_call_depth {
+        sm.set_max_call_depth(max_call_depth);
+    }
+    sm.set_time_limit(limits.time_limit);
+    if let Some(max_allocated) = limits.max_allocated {
+        sm.set_max_allocated(max_allocated);
+    }
+}
+
+// Loads a .hsc source file or a .hsb bytecode file, returning the source
+// text if there is any so errors can quote it.
+fn load_file(sm: &mut StackMachine, file_path: &str) -> Option<String> {
+    let content = read_file(file_path);
+    if bytecode::is_bytecode(&content) {
+        if let Err(e) = sm.load_bytecode(Some(file_path), &content) {
+            fail(e, None);
         }
-        let sections: Vec<&str> = line.split(" ").collect();
-        let instruction = sections.get(0);
-        match instruction
-        {
-            Some(&"pushi") => {
-                let value_input = sections.get(1).expect("Push argument missing");
-                let value = value_input.parse::<i32>().unwrap();
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::PUSHINT, value: Some(Value { int_value: value, float_value: 0.0, value_type: ValueType::INT, }), label: String::from("") }));
-            },
-            Some(&"pushf") => {
-                let value_input = sections.get(1).expect("Push argument missing");
-                let value = value_input.parse::<f32>().unwrap();
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::PUSHFLOAT, value: Some(Value { int_value: 0, float_value: value, value_type: ValueType::FLOAT}),  label: String::from("") }));
-            },
-            Some(&"pop") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::POP, value: None, label: String::from("") }));
-            },
-            Some(&"addi") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::ADDI, value: None, label: String::from("") }));
-            },
-            Some(&"subi") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::SUBI, value: None, label: String::from("") }));
-            },
-            Some(&"muli") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::MULI, value: None, label: String::from("") }));
-            },
-            Some(&"divi") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::DIVI, value: None, label: String::from("") }));
-            },
-            Some(&"addf") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::ADDF, value: None, label: String::from("") }));
-            },
-            Some(&"subf") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::SUBF, value: None, label: String::from("") }));
-            },
-            Some(&"mulf") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::MULF, value: None, label: String::from("") }));
-            },
-            Some(&"divf") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::DIVF, value: None, label: String::from("") }));
-            },
-            Some(&"print") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::PRINT, value: None, label: String::from("") }));
-            },
-            Some(&"printl") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::PRINTLINE, value: None, label: String::from("") }));
-            },
-            Some(&"printa") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::PRINTASCII, value: None, label: String::from("") }));
-            },
-            Some(&"inputi") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::INPUTINT, value: None, label: String::from("") }));
-            },
-            Some(&"inputf") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::INPUTFLOAT, value: None, label: String::from("") }));
-            },
-            Some(&"label") => {
-                let label = sections.get(1).expect("Lbl argument missing");
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::LABEL, value: None, label: label.to_string() }));
-            },
-            Some(&"j") => {
-                let label = sections.get(1).expect("J argument missing");
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::J, value: None, label: label.to_string() }));
-            },
-            Some(&"je") => {
-                let label = sections.get(1).expect("JE argument missing");
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::JE, value: None, label: label.to_string() }));
-            },
-            Some(&"jn") => {
-                let label = sections.get(1).expect("JN argument missing");
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::JN, value: None, label: label.to_string() }));
-            },
-            Some(&"jl") => {
-                let label = sections.get(1).expect("JL argument missing");
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::JL, value: None, label: label.to_string() }));
-            },
-            Some(&"jg") => {
-                let label = sections.get(1).expect("JG argument missing");
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::JG, value: None, label: label.to_string() }));
-            },
-            Some(&"jle") => {
-                let label = sections.get(1).expect("JLE argument missing");
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::JLE, value: None, label: label.to_string() }));
-            },
-            Some(&"jge") => {
-                let label = sections.get(1).expect("JGE argument missing");
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::JGE, value: None, label: label.to_string() }));
-            },
-            Some(&"toi") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::TOINT, value: None, label: String::from("") }));
-            },
-            Some(&"dup") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::DUPLICATE, value: None, label: String::from("") }));
-            },
-            Some(&"tof") => {
-                sm.instructions.push(Box::new(Instruction { instruction_type: InstructionType::TOFLOAT, value: None, label: String::from("") }));
-            },
-            Some(&_) => panic!("Instruction not implemented"),
-            None => todo!()
+        None
+    } else {
+        let source = String::from_utf8_lossy(&content).into_owned();
+        if let Err(e) = sm.load_named_source(file_path, &source) {
+            fail(e, Some(&source));
         }
+        Some(source)
     }
-    let mut pointer: usize = 0;
-    let size: usize = sm.instructions.len();
-    while pointer < size {
-        let instruction: &Box<Instruction> = sm.instructions.get(pointer).unwrap();
-        pointer += 1;
-        match instruction.instruction_type {
-            InstructionType::PUSHINT => {
-                let data = instruction.value.as_ref().unwrap();
-                sm.stack.push(Value { int_value: data.int_value, float_value: data.float_value, value_type: ValueType::INT });
-            },
-            InstructionType::PUSHFLOAT => {
-                let data = instruction.value.as_ref().unwrap();
-                sm.stack.push(Value { int_value: data.int_value, float_value: data.float_value, value_type: ValueType::FLOAT });
-            },
-            InstructionType::POP => {
-                sm.stack.pop();
-            },
-            InstructionType::ADDI => {
-                let second = sm.stack.pop().expect(STACK_UNDERFLOW);
-                let first = sm.stack.pop().expect(STACK_UNDERFLOW);
-                assert!(second.value_type == ValueType::INT, "{}", TOP_IS_NOT_INTEGER);
-                assert!(first.value_type == ValueType::INT, "{}", TOP_IS_NOT_INTEGER);
-                sm.stack.push(Value { int_value: first.int_value + second.int_value, float_value: 0.0, value_type: ValueType::INT, });
-            },
-            InstructionType::SUBI => {
-                let second = sm.stack.pop().expect(STACK_UNDERFLOW);
-                let first = sm.stack.pop().expect(STACK_UNDERFLOW);
-                assert!(second.value_type == ValueType::INT, "{}", TOP_IS_NOT_INTEGER);
-                assert!(first.value_type == ValueType::INT, "{}", TOP_IS_NOT_INTEGER);
-                sm.stack.push(Value { int_value: first.int_value - second.int_value, float_value: 0.0, value_type: ValueType::INT, });
-            },
-            InstructionType::MULI => {
-                let second = sm.stack.pop().expect(STACK_UNDERFLOW);
-                let first = sm.stack.pop().expect(STACK_UNDERFLOW);
-                assert!(second.value_type == ValueType::INT, "{}", TOP_IS_NOT_INTEGER);
-                assert!(first.value_type == ValueType::INT, "{}", TOP_IS_NOT_INTEGER);
-                sm.stack.push(Value { int_value: first.int_value * second.int_value, float_value: 0.0, value_type: ValueType::INT, });
-            },
-            InstructionType::DIVI => {
-                let second = sm.stack.pop().expect(STACK_UNDERFLOW);
-                let first = sm.stack.pop().expect(STACK_UNDERFLOW);
-                assert!(second.value_type == ValueType::INT, "{}", TOP_IS_NOT_INTEGER);
-                assert!(first.value_type == ValueType::INT, "{}", TOP_IS_NOT_INTEGER);
-                sm.stack.push(Value { int_value: first.int_value / second.int_value, float_value: 0.0, value_type: ValueType::INT, });
-            },
-            InstructionType::ADDF => {
-                let second = sm.stack.pop().expect(STACK_UNDERFLOW);
-                let first = sm.stack.pop().expect(STACK_UNDERFLOW);
-                assert!(second.value_type == ValueType::FLOAT, "{}", TOP_IS_NOT_FLOAT);
-                assert!(first.value_type == ValueType::FLOAT, "{}", TOP_IS_NOT_FLOAT);
-                sm.stack.push(Value { int_value: 0, float_value: first.float_value + second.float_value, value_type: ValueType::FLOAT, });
-            },
-            InstructionType::SUBF => {
-                let second = sm.stack.pop().expect(STACK_UNDERFLOW);
-                let first = sm.stack.pop().expect(STACK_UNDERFLOW);
-                assert!(second.value_type == ValueType::FLOAT, "{}", TOP_IS_NOT_FLOAT);
-                assert!(first.value_type == ValueType::FLOAT, "{}", TOP_IS_NOT_FLOAT);
-                sm.stack.push(Value { int_value: 0, float_value: first.float_value - second.float_value, value_type: ValueType::FLOAT, });
-            },
-            InstructionType::MULF => {
-                let second = sm.stack.pop().expect(STACK_UNDERFLOW);
-                let first = sm.stack.pop().expect(STACK_UNDERFLOW);
-                assert!(second.value_type == ValueType::FLOAT, "{}", TOP_IS_NOT_FLOAT);
-                assert!(first.value_type == ValueType::FLOAT, "{}", TOP_IS_NOT_FLOAT);
-                sm.stack.push(Value { int_value: 0, float_value: first.float_value * second.float_value, value_type: ValueType::FLOAT, });
-            },
-            InstructionType::DIVF => {
-                let second = sm.stack.pop().expect(STACK_UNDERFLOW);
-                let first = sm.stack.pop().expect(STACK_UNDERFLOW);
-                assert!(second.value_type == ValueType::FLOAT, "{}", TOP_IS_NOT_FLOAT);
-                assert!(first.value_type == ValueType::FLOAT, "{}", TOP_IS_NOT_FLOAT);
-                sm.stack.push(Value { int_value: 0, float_value: first.float_value / second.float_value, value_type: ValueType::FLOAT, });
-            },
-            InstructionType::PRINT => {
-                let top = sm.stack.pop().expect(STACK_UNDERFLOW);
-                match top.value_type {
-                    ValueType::INT => {
-                        print!("{}", top.int_value);
-                    },
-                    ValueType::FLOAT => {
-                        print!("{}", top.float_value);
-                    }
-                }
-                sm.stack.push(top);
-            },
-            InstructionType::PRINTLINE => {
-                let top = sm.stack.pop().expect(STACK_UNDERFLOW);
-                match top.value_type {
-                    ValueType::INT => {
-                        println!("{}", top.int_value);
-                    },
-                    ValueType::FLOAT => {
-                        println!("{}", top.float_value);
-                    }
-                }
-                sm.stack.push(top);
-            },
-            InstructionType::PRINTASCII => {
-                let top = sm.stack.pop().expect(STACK_UNDERFLOW);
-                match top.value_type {
-                    ValueType::INT => {
-                        print!("{}", char::from_u32(top.int_value.try_into().unwrap()).unwrap());
-                    },
-                    ValueType::FLOAT => {
-                        panic!("{}", TOP_IS_NOT_INTEGER);
-                    }
-                }
-                sm.stack.push(top);
-            },
-            InstructionType::INPUTINT => {
-                let mut line  = String::new();
-                std::io::stdin().read_line(&mut line).unwrap();
-                let value : i32 = line.trim().parse().unwrap();
-                sm.stack.push(Value { int_value: value, float_value: 0.0, value_type: ValueType::INT, });
-            },
-            InstructionType::INPUTFLOAT => {
-                let mut line  = String::new();
-                std::io::stdin().read_line(&mut line).unwrap();
-                let value : f32 = line.trim().parse().unwrap();
-                sm.stack.push(Value { int_value: 0, float_value: value, value_type: ValueType::FLOAT, });
-            }
-            InstructionType::LABEL => { }
-            InstructionType::J => {
-                pointer = sm.get_pointer(&instruction.label);
-            },
-            InstructionType::JE => {
-                let second = sm.stack.pop().expect(STACK_UNDERFLOW);
-                let first = sm.stack.pop().expect(STACK_UNDERFLOW);
-                if second.value_type != first.value_type {
-                    panic!("{}", &DIFFERENT_TYPES);
-                }
-                match first.value_type {
-                    ValueType::INT => {
-                        if first.int_value == second.int_value {
-                            pointer = sm.get_pointer(&instruction.label);
-                        }
-                    },
-                    ValueType::FLOAT => {
-                        if first.float_value == second.float_value {
-                            pointer = sm.get_pointer(&instruction.label);
-                        }
-                    }
-                }
-                sm.stack.push(first);
-            },
-            InstructionType::JN => {
-                let second = sm.stack.pop().expect(STACK_UNDERFLOW);
-                let first = sm.stack.pop().expect(STACK_UNDERFLOW);
-                if second.value_type != first.value_type {
-                    panic!("{}", &DIFFERENT_TYPES);
-                }
-                match first.value_type {
-                    ValueType::INT => {
-                        if first.int_value != second.int_value {
-                            pointer = sm.get_pointer(&instruction.label);
-                        }
-                    },
-                    ValueType::FLOAT => {
-                        if first.float_value != second.float_value {
-                            pointer = sm.get_pointer(&instruction.label);
-                        }
-                    }
-                }
-                sm.stack.push(first);
-            },
-            InstructionType::JL => {
-                let second = sm.stack.pop().expect(STACK_UNDERFLOW);
-                let first = sm.stack.pop().expect(STACK_UNDERFLOW);
-                if second.value_type != first.value_type {
-                    panic!("{}", &DIFFERENT_TYPES);
-                }
-                match first.value_type {
-                    ValueType::INT => {
-                        if first.int_value < second.int_value {
-                            pointer = sm.get_pointer(&instruction.label);
-                        }
-                    },
-                    ValueType::FLOAT => {
-                        if first.float_value < second.float_value {
-                            pointer = sm.get_pointer(&instruction.label);
-                        }
-                    }
-                }
-                sm.stack.push(first);
-            },
-            InstructionType::JG => {
-                let second = sm.stack.pop().expect(STACK_UNDERFLOW);
-                let first = sm.stack.pop().expect(STACK_UNDERFLOW);
-                if second.value_type != first.value_type {
-                    panic!("{}", &DIFFERENT_TYPES);
-                }
-                match first.value_type {
-                    ValueType::INT => {
-                        if first.int_value > second.int_value {
-                            pointer = sm.get_pointer(&instruction.label);
-                        }
-                    },
-                    ValueType::FLOAT => {
-                        if first.float_value > second.float_value {
-                            pointer = sm.get_pointer(&instruction.label);
-                        }
-                    }
-                }
-                sm.stack.push(first);
-            },
-            InstructionType::JLE => {
-                let second = sm.stack.pop().expect(STACK_UNDERFLOW);
-                let first = sm.stack.pop().expect(STACK_UNDERFLOW);
-                if second.value_type != first.value_type {
-                    panic!("{}", &DIFFERENT_TYPES);
-                }
-                match first.value_type {
-                    ValueType::INT => {
-                        if first.int_value <= second.int_value {
-                            pointer = sm.get_pointer(&instruction.label);
-                        }
-                    },
-                    ValueType::FLOAT => {
-                        if first.float_value <= second.float_value {
-                            pointer = sm.get_pointer(&instruction.label);
-                        }
-                    }
-                }
-                sm.stack.push(first);
-            },
-            InstructionType::JGE => {
-                let second = sm.stack.pop().expect(STACK_UNDERFLOW);
-                let first = sm.stack.pop().expect(STACK_UNDERFLOW);
-                if second.value_type != first.value_type {
-                    panic!("{}", &DIFFERENT_TYPES);
-                }
-                match first.value_type {
-                    ValueType::INT => {
-                        if first.int_value >= second.int_value {
-                            pointer = sm.get_pointer(&instruction.label);
-                        }
-                    },
-                    ValueType::FLOAT => {
-                        if first.float_value >= second.float_value {
-                            pointer = sm.get_pointer(&instruction.label);
-                        }
-                    }
-                }
-                sm.stack.push(first);
-            },
-            InstructionType::TOINT => {
-                let top = sm.stack.pop().expect(STACK_UNDERFLOW);
-                assert!(top.value_type == ValueType::FLOAT, "{}", TOP_IS_NOT_FLOAT);
-                let val: i32 = top.float_value as i32;
-                sm.stack.push(Value { int_value: val, float_value: 0.0, value_type: ValueType::INT, });
-            },
-            InstructionType::TOFLOAT => {
-                let top = sm.stack.pop().expect(STACK_UNDERFLOW);
-                assert!(top.value_type == ValueType::INT, "{}", TOP_IS_NOT_INTEGER);
-                let val: f32 = top.int_value as f32;
-                sm.stack.push(Value { int_value: 0, float_value: val, value_type: ValueType::FLOAT, });
-            }
-            InstructionType::DUPLICATE => {
-                let top = sm.stack.pop().expect(STACK_UNDERFLOW).clone();
-                sm.stack.push(top);
-                sm.stack.push(Value { int_value: top.int_value, float_value: top.float_value, value_type: ValueType::INT });
-                sm.stack.push(Value { int_value: top.int_value, float_value: top.float_value, value_type: ValueType::INT });
-            }
+}
+
+fn run_file(file_path: &str, options: &RunOptions) {
+    println!("Welcome to Huw's Stack Machine");
+    let mut sm = StackMachine::new();
+    configure(&mut sm, options);
+    let source = load_file(&mut sm, file_path);
+    let result = match &options.trace {
+        Some(trace) => trace_run(&mut sm, trace),
+        None if options.profile || options.profile_folded.is_some() => profile_run(&mut sm, options),
+        None => sm.run(),
+    };
+    if let Err(e) = result {
+        fail(e, source.as_deref());
+    }
+}
+
+// Runs the loaded program, writing its execution trace to a file.
+fn trace_run(sm: &mut StackMachine, options: &TraceOptions) -> Result<(), VmError> {
+    let file = File::create(&options.file_path).unwrap_or_else(|e| {
+        eprintln!("error: Cannot write file '{}': {}", options.file_path, e);
+        process::exit(1);
+    });
+    let mut tracer = Tracer::new(sm, Box::new(BufWriter::new(file)));
+    if let Err(e) = tracer.trace_between(options.from.as_deref(), options.to.as_deref()) {
+        usage_error(&e.to_string());
+    }
+    tracer.run()
+}
+
+// Runs the loaded program under the profiler, then prints the report to
+// stderr and writes the folded stacks if they were asked for. The profile is
+// written even if the program fails.
+fn profile_run(sm: &mut StackMachine, options: &RunOptions) -> Result<(), VmError> {
+    let mut profiler = Profiler::new(sm);
+    let result = profiler.run();
+    if options.profile {
+        eprint!("{}", profiler.report());
+    }
+    if let Some(file_path) = &options.profile_folded {
+        if let Err(e) = fs::write(file_path, profiler.folded()) {
+            eprintln!("error: Cannot write file '{}': {}", file_path, e);
+            process::exit(1);
         }
     }
+    result
+}
+
+// Reads stdin without buffering ahead, so that debugger commands or REPL
+// lines and the program's input can share it, each reader taking only the
+// lines it asks for.
+fn unbuffered_stdin() -> Box<BufReader<io::Stdin>> {
+    Box::new(BufReader::with_capacity(1, io::stdin()))
+}
+
+// Runs a file under the debugger, which reads its commands from stdin.
+fn debug_file(options: &RunOptions) {
+    if options.trace.is_some() || options.profile || options.profile_folded.is_some() {
+        usage_error("--trace and --profile cannot be used with debug");
+    }
+    let file_path = options.file_path.as_deref().unwrap_or_else(|| usage_error("No file given"));
+    println!("Welcome to Huw's Stack Machine");
+    let mut sm = StackMachine::with_io(unbuffered_stdin(), Box::new(io::stdout()));
+    configure(&mut sm, options);
+    let source = load_file(&mut sm, file_path);
+    if let Err(e) = Debugger::new(&mut sm, source.as_deref()).run(&mut *unbuffered_stdin(), &mut io::stdout()) {
+        fail(e, source.as_deref());
+    }
+}
+
+// Starts the REPL on stdin.
+fn repl(options: &RunOptions) {
+    println!("Welcome to Huw's Stack Machine");
+    println!("Enter instructions to run them, or :help for commands");
+    let mut sm = StackMachine::with_io(unbuffered_stdin(), Box::new(io::stdout()));
+    configure(&mut sm, options);
+    if let Err(e) = Repl::new(&mut sm).run(&mut *unbuffered_stdin(), &mut io::stdout()) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn assemble_file(input_path: &str, output_path: &str) {
+    let content = read_file(input_path);
+    let source = String::from_utf8_lossy(&content);
+    let bytes = match parse(&source, Some(input_path)).and_then(assemble).and_then(|i| bytecode::encode(&i)) {
+        Ok(bytes) => bytes,
+        Err(e) => fail(e, Some(&source)),
+    };
+    if let Err(e) = fs::write(output_path, bytes) {
+        eprintln!("error: Cannot write file '{}': {}", output_path, e);
+        process::exit(1);
+    }
+}
+
+fn disassemble_file(input_path: &str) {
+    let content = read_file(input_path);
+    match bytecode::decode(&content, Some(input_path)) {
+        Ok(instructions) => print!("{}", bytecode::disassemble(&instructions)),
+        Err(e) => fail(e, None),
+    }
 }
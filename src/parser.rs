@@ -0,0 +1,140 @@
+use std::rc::Rc;
+
+use crate::error::{Location, VmError};
+use crate::instruction::{Instruction, InstructionType, OperandType, Signature, REGISTER_COUNT};
+use crate::value::Value;
+
+/// Parses `.hsc` source into a list of instructions, one per line. Blank lines
+/// and comments starting with `;` or `#` are skipped, and sections of a line may
+/// be separated by any amount of whitespace. String literals may contain
+/// whitespace and comment characters. Anything after the arguments an
+/// instruction takes, other than a comment, is an error. `file` is only used
+/// to label the locations recorded on each instruction.
+pub fn parse(source: &str, file: Option<&str>) -> Result<Vec<Instruction>, VmError> {
+    parse_from(source, file, 1)
+}
+
+// Same as `parse`, but numbers the lines of `source` from `first_line`, for
+// source that continues earlier text such as a line typed into the REPL.
+pub(crate) fn parse_from(source: &str, file: Option<&str>, first_line: usize) -> Result<Vec<Instruction>, VmError> {
+    let file: Option<Rc<str>> = file.map(Rc::from);
+    let mut instructions = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let sections = tokenize(strip_comment(line));
+        if sections.is_empty() {
+            continue;
+        }
+        let line_number = first_line + index;
+        let location = |column: usize| Location::new(file.clone(), line_number, column);
+        let (name_column, name) = sections[0];
+        let instruction_type = InstructionType::from_mnemonic(name)
+            .ok_or_else(|| VmError::UnknownInstruction { name: name.to_string(), location: location(name_column) })?;
+        if let Some(&(column, extra)) = sections.get(1 + instruction_type.operand_type().argument_count()) {
+            return Err(VmError::UnexpectedArgument { argument: extra.to_string(), location: location(column) });
+        }
+        let argument = |n: usize| {
+            sections.get(n).copied().ok_or_else(|| VmError::MissingArgument { instruction: name.to_string(), location: location(name_column) })
+        };
+        let register = |n: usize| {
+            let (column, register_input) = argument(n)?;
+            parse_register(register_input).ok_or_else(|| VmError::UnknownRegister { name: register_input.to_string(), location: location(column) })
+        };
+        let count = |n: usize| {
+            let (column, count_input) = argument(n)?;
+            // Counts are held in 32 bits in bytecode, so larger ones are
+            // rejected here rather than truncated later.
+            count_input.parse::<u32>().map(|n| n as usize).map_err(|_| VmError::BadLiteral { literal: count_input.to_string(), location: location(column) })
+        };
+        let mut instruction = match instruction_type.operand_type()
+        {
+            OperandType::NONE => Instruction::new(instruction_type),
+            OperandType::VALUE(value_type) => {
+                let (column, value_input) = argument(1)?;
+                let value = Value::parse(value_type, value_input)
+                    .ok_or_else(|| VmError::BadLiteral { literal: value_input.to_string(), location: location(column) })?;
+                Instruction::with_value(instruction_type, value)
+            },
+            OperandType::LABEL | OperandType::VARIABLE => Instruction::with_label(instruction_type, argument(1)?.1),
+            OperandType::SLOT => Instruction::with_slot(instruction_type, count(1)?),
+            OperandType::FUNCTION => {
+                let signature = Signature { args: count(2)?, locals: count(3)? };
+                Instruction::with_signature(instruction_type, argument(1)?.1, signature)
+            },
+            OperandType::REGISTERS(count) => {
+                let mut registers = [0; 2];
+                for (n, r) in registers.iter_mut().take(count).enumerate() {
+                    *r = register(n + 1)?;
+                }
+                Instruction::with_registers(instruction_type, registers)
+            },
+        };
+        instruction.location = location(name_column);
+        instructions.push(instruction);
+    }
+    Ok(instructions)
+}
+
+// Reads a register name, `r0` to `r15`.
+fn parse_register(name: &str) -> Option<u8> {
+    let number: u8 = name.strip_prefix('r')?.parse().ok()?;
+    (usize::from(number) < REGISTER_COUNT).then_some(number)
+}
+
+// Drops everything from the first `;` or `#` outside a string literal to the
+// end of the line.
+fn strip_comment(line: &str) -> &str {
+    let mut quotes = Quotes::default();
+    for (offset, c) in line.char_indices() {
+        if !quotes.inside(c) && (c == ';' || c == '#') {
+            return &line[..offset];
+        }
+    }
+    line
+}
+
+// Splits a line on whitespace outside string literals, keeping the 1-based
+// column of each section.
+fn tokenize(line: &str) -> Vec<(usize, &str)> {
+    let mut sections = Vec::new();
+    let mut start: Option<(usize, usize)> = None;
+    let mut quotes = Quotes::default();
+    for (column, (offset, c)) in line.char_indices().enumerate() {
+        let separator = !quotes.inside(c) && c.is_whitespace();
+        match (separator, start) {
+            (false, None) => start = Some((column + 1, offset)),
+            (true, Some((start_column, start_offset))) => {
+                sections.push((start_column, &line[start_offset..offset]));
+                start = None;
+            },
+            _ => {}
+        }
+    }
+    if let Some((start_column, start_offset)) = start {
+        sections.push((start_column, &line[start_offset..]));
+    }
+    sections
+}
+
+// Tracks whether a scan through a line is inside a double quoted string,
+// skipping over escaped quotes.
+#[derive(Default)]
+struct Quotes {
+    open: bool,
+    escaped: bool,
+}
+
+impl Quotes {
+    // Moves past `c`, returning whether it is part of a string literal,
+    // including the quotes themselves.
+    fn inside(&mut self, c: char) -> bool {
+        if self.escaped {
+            self.escaped = false;
+        } else if self.open && c == '\\' {
+            self.escaped = true;
+        } else if c == '"' {
+            self.open = !self.open;
+            return true;
+        }
+        self.open
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::error::VmError;
+use crate::instruction::InstructionType;
+use crate::machine::StackMachine;
+
+// Number of instructions listed in the hot spot section of a report.
+const HOT_INSTRUCTIONS: usize = 20;
+
+// Name of the block of instructions before the first label.
+const START_BLOCK: &str = "<start>";
+
+/// Runs a loaded program while counting how often each instruction executes
+/// and timing each one. Counts and times are also summed per block, the
+/// instructions from one `label` or `func` up to the next, and per opcode.
+/// Times include the cost of taking them, so they are best compared with each
+/// other rather than with a run without the profiler.
+pub struct Profiler<'a> {
+    sm: &'a mut StackMachine,
+    counts: Vec<u64>,
+    times: Vec<Duration>,
+    // Block index of each instruction, and the name of each block.
+    blocks: Vec<usize>,
+    block_names: Vec<String>,
+    // Blocks that made each active call, outermost first.
+    callers: Vec<usize>,
+    // Instructions executed under each distinct chain of calling blocks,
+    // ending with the block they are in.
+    stacks: HashMap<Vec<usize>, u64>,
+    elapsed: Duration,
+}
+
+impl<'a> Profiler<'a> {
+    /// Profiles the program loaded into `sm`.
+    pub fn new(sm: &'a mut StackMachine) -> Profiler<'a> {
+        let length = sm.instructions().len();
+        let mut blocks = Vec::with_capacity(length);
+        let mut block_names = vec![String::from(START_BLOCK)];
+        for instruction in sm.instructions() {
+            if instruction.instruction_type.defines_label() {
+                block_names.push(instruction.label.clone());
+            }
+            blocks.push(block_names.len() - 1);
+        }
+        Profiler { sm, counts: vec![0; length], times: vec![Duration::ZERO; length], blocks, block_names,
+            callers: Vec::new(), stacks: HashMap::new(), elapsed: Duration::ZERO }
+    }
+
+    /// Runs the program to the end. The profile covers everything executed
+    /// before an error, so it can still be reported when the program fails.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        let mut path = Vec::new();
+        let start = Instant::now();
+        let result = loop {
+            if self.sm.is_halted() {
+                break self.sm.flush();
+            }
+            let pointer = self.sm.pointer();
+            let depth = self.sm.call_depth();
+            let before = Instant::now();
+            let stepped = self.sm.step();
+            self.times[pointer] += before.elapsed();
+            self.counts[pointer] += 1;
+            path.clear();
+            path.extend_from_slice(&self.callers);
+            path.push(self.blocks[pointer]);
+            match self.stacks.get_mut(&path) {
+                Some(count) => *count += 1,
+                None => {
+                    self.stacks.insert(path.clone(), 1);
+                },
+            }
+            if let Err(e) = stepped {
+                break Err(e);
+            }
+            match self.sm.call_depth() {
+                d if d > depth => self.callers.push(self.blocks[pointer]),
+                d if d < depth => {
+                    self.callers.pop();
+                },
+                _ => {},
+            }
+        };
+        self.elapsed += start.elapsed();
+        result
+    }
+
+    /// A report of where the program spent its time: the most executed
+    /// instructions, then every block and every opcode that ran, each sorted
+    /// with the busiest first.
+    pub fn report(&self) -> String {
+        let instructions = self.sm.instructions();
+        let total: u64 = self.counts.iter().sum();
+        let mut out = format!("Profile: {} instructions executed in {:?}\n", total, self.elapsed);
+
+        let mut hot: Vec<usize> = (0..self.counts.len()).filter(|&i| self.counts[i] > 0).collect();
+        hot.sort_by(|&a, &b| self.counts[b].cmp(&self.counts[a]).then(a.cmp(&b)));
+        out.push_str("\nHot instructions\n");
+        let _ = writeln!(out, "{:>12} {:>12} {:>6} {:>6}  instruction", "count", "time", "ip", "line");
+        for &i in hot.iter().take(HOT_INSTRUCTIONS) {
+            let time = format!("{:?}", self.times[i]);
+            let _ = writeln!(out, "{:>12} {:>12} {:>6} {:>6}  {}", self.counts[i], time, i, instructions[i].location.line, instructions[i]);
+        }
+
+        let mut blocks = vec![(0, Duration::ZERO); self.block_names.len()];
+        for (i, &block) in self.blocks.iter().enumerate() {
+            blocks[block].0 += self.counts[i];
+            blocks[block].1 += self.times[i];
+        }
+        let mut order: Vec<usize> = (0..blocks.len()).filter(|&b| blocks[b].0 > 0).collect();
+        order.sort_by(|&a, &b| blocks[b].0.cmp(&blocks[a].0).then(a.cmp(&b)));
+        out.push_str("\nBlocks\n");
+        let _ = writeln!(out, "{:>12} {:>12}  block", "count", "time");
+        for b in order {
+            let time = format!("{:?}", blocks[b].1);
+            let _ = writeln!(out, "{:>12} {:>12}  {}", blocks[b].0, time, self.block_names[b]);
+        }
+
+        let mut opcodes: HashMap<InstructionType, (u64, Duration)> = HashMap::new();
+        for (i, instruction) in instructions.iter().enumerate() {
+            if self.counts[i] > 0 {
+                let entry = opcodes.entry(instruction.instruction_type).or_default();
+                entry.0 += self.counts[i];
+                entry.1 += self.times[i];
+            }
+        }
+        let mut opcodes: Vec<_> = opcodes.into_iter().collect();
+        opcodes.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then(a.0.mnemonic().cmp(b.0.mnemonic())));
+        out.push_str("\nOpcodes\n");
+        let _ = writeln!(out, "{:>12} {:>12} {:>12}  opcode", "count", "time", "mean");
+        for (instruction_type, (count, time)) in opcodes {
+            let mean = format!("{:?}", Duration::from_nanos((time.as_nanos() / u128::from(count)) as u64));
+            let _ = writeln!(out, "{:>12} {:>12} {:>12}  {}", count, format!("{:?}", time), mean, instruction_type.mnemonic());
+        }
+        out
+    }
+
+    /// The profile in the folded stack format read by flame graph tools: one
+    /// line per chain of calling blocks, separated by `;`, followed by the
+    /// number of instructions executed in the last block of the chain.
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self.stacks.iter().map(|(path, count)| {
+            let names: Vec<&str> = path.iter().map(|&b| self.block_names[b].as_str()).collect();
+            format!("{} {}\n", names.join(";"), count)
+        }).collect();
+        lines.sort();
+        lines.concat()
+    }
+}
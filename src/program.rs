@@ -0,0 +1,60 @@
+use crate::error::VmError;
+use crate::instruction::{Instruction, InstructionType, OperandType, Signature};
+use crate::value::Value;
+
+/// An instruction lowered for execution. The operand is inline and its meaning
+/// depends on the instruction: the resolved target of a jump, the index of a
+/// pushed value in `Program::constants`, the slot of a variable, the index of
+/// a `func` in `Program::signatures` or, for register instructions, the
+/// destination register in the low byte and the source in the next.
+#[derive(Clone, Copy, Debug)]
+pub struct Op {
+    pub instruction_type: InstructionType,
+    pub operand: u32,
+}
+
+/// A loaded program in the form the machine runs: one compact `Op` per
+/// instruction in a contiguous array. Labels, names and locations are left in
+/// the assembled instructions, which are only read to report errors.
+#[derive(Clone, Debug, Default)]
+pub struct Program {
+    pub ops: Vec<Op>,
+    pub constants: Vec<Value>,
+    pub signatures: Vec<Signature>,
+}
+
+impl Program {
+    /// Lowers assembled instructions. Fails if an operand the assembler should
+    /// have filled in is missing.
+    pub fn lower(instructions: &[Instruction]) -> Result<Program, VmError> {
+        let mut program = Program { ops: Vec::with_capacity(instructions.len()), ..Program::default() };
+        for i in instructions {
+            let operand = match i.instruction_type.operand_type() {
+                OperandType::NONE => 0,
+                OperandType::VALUE(_) => {
+                    let value = i.value.clone().ok_or_else(|| VmError::MissingArgument { instruction: i.instruction_type.mnemonic().to_string(), location: i.location.clone() })?;
+                    program.constants.push(value);
+                    (program.constants.len() - 1) as u32
+                },
+                OperandType::LABEL if i.instruction_type == InstructionType::LABEL => 0,
+                OperandType::LABEL | OperandType::VARIABLE | OperandType::SLOT => {
+                    let target = i.target.ok_or_else(|| VmError::UndefinedLabel { label: i.label.clone(), location: i.location.clone() })?;
+                    narrow(target, i)?
+                },
+                OperandType::FUNCTION => {
+                    program.signatures.push(i.signature.unwrap_or_default());
+                    (program.signatures.len() - 1) as u32
+                },
+                OperandType::REGISTERS(_) => u32::from(i.registers[0]) | u32::from(i.registers[1]) << 8,
+            };
+            program.ops.push(Op { instruction_type: i.instruction_type, operand });
+        }
+        Ok(program)
+    }
+}
+
+// Narrows an operand to the 32 bits an `Op` and bytecode hold it in, failing
+// rather than truncating one that does not fit.
+pub(crate) fn narrow(operand: usize, i: &Instruction) -> Result<u32, VmError> {
+    u32::try_from(operand).map_err(|_| VmError::BadLiteral { literal: operand.to_string(), location: i.location.clone() })
+}
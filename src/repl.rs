@@ -0,0 +1,130 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use crate::bytecode;
+use crate::error::VmError;
+use crate::instruction::Instruction;
+use crate::machine::StackMachine;
+use crate::parser;
+use crate::value::format_list;
+
+// File name that errors in typed lines are reported against.
+const REPL_FILE: &str = "<repl>";
+
+const HELP: &str = "\
+Instructions are run as soon as they are entered. Commands:
+  :load FILE   run a .hsc or .hsb file against the current state
+  :reset       clear the stack, variables, registers and labels
+  :stack       show the stack
+  :history     list the lines entered so far
+  !N           enter line N of the history again
+  :help        show this message
+  :quit        leave
+";
+
+/// Reads instructions a line at a time and runs each one as soon as it is
+/// entered, against a machine whose stack, variables and registers carry
+/// over from line to line. Typed lines are added to the end of the loaded
+/// program, so a jump back to a label entered earlier runs the lines after it
+/// again. The stack is shown after every line.
+pub struct Repl<'a> {
+    sm: &'a mut StackMachine,
+    // Names and text of everything loaded, with the typed lines first, so
+    // errors can quote the line they come from.
+    sources: Vec<(String, String)>,
+    history: Vec<String>,
+}
+
+impl<'a> Repl<'a> {
+    pub fn new(sm: &'a mut StackMachine) -> Repl<'a> {
+        Repl { sm, sources: vec![(String::from(REPL_FILE), String::new())], history: Vec::new() }
+    }
+
+    /// Reads lines from `lines`, writing prompts, stacks and errors to `out`,
+    /// until `:quit` or the end of the input. Errors from the program are
+    /// reported and the session carries on.
+    pub fn run(&mut self, lines: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<()> {
+        let mut line = String::new();
+        loop {
+            write!(out, "> ")?;
+            out.flush()?;
+            line.clear();
+            if lines.read_line(&mut line)? == 0 {
+                return writeln!(out);
+            }
+            if line.trim() == ":quit" {
+                return Ok(());
+            }
+            self.enter(line.trim(), out)?;
+        }
+    }
+
+    // Handles one line: a command, a history reference or an instruction.
+    fn enter(&mut self, line: &str, out: &mut dyn Write) -> io::Result<()> {
+        if line.is_empty() {
+            return Ok(());
+        }
+        if let Some(number) = line.strip_prefix('!') {
+            let entry = number.parse::<usize>().ok().and_then(|n| n.checked_sub(1)).and_then(|n| self.history.get(n)).cloned();
+            return match entry {
+                Some(entry) => {
+                    writeln!(out, "{}", entry)?;
+                    self.enter(&entry, out)
+                },
+                None => writeln!(out, "No history entry '{}'", number),
+            };
+        }
+        self.history.push(line.to_string());
+        let (command, argument) = line.split_once(char::is_whitespace).map_or((line, ""), |(c, a)| (c, a.trim()));
+        match command {
+            ":load" if !argument.is_empty() => self.load(argument, out),
+            ":reset" => {
+                self.sm.reset();
+                self.show_stack(out)
+            },
+            ":stack" => self.show_stack(out),
+            ":history" => self.history.iter().enumerate().try_for_each(|(i, entry)| writeln!(out, "{:>4}  {}", i + 1, entry)),
+            ":help" => write!(out, "{}", HELP),
+            _ if command.starts_with(':') => writeln!(out, "Unknown command '{}', :help lists the commands", line),
+            _ => {
+                let typed = &mut self.sources[0].1;
+                let number = typed.lines().count() + 1;
+                typed.push_str(line);
+                typed.push('\n');
+                self.execute(parser::parse_from(line, Some(REPL_FILE), number), out)
+            },
+        }
+    }
+
+    // Runs a .hsc or .hsb file as if its lines were entered one after another.
+    fn load(&mut self, path: &str, out: &mut dyn Write) -> io::Result<()> {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(e) => return writeln!(out, "error: Cannot read file '{}': {}", path, e),
+        };
+        let instructions = if bytecode::is_bytecode(&content) {
+            bytecode::decode(&content, Some(path))
+        } else {
+            let source = String::from_utf8_lossy(&content).into_owned();
+            let instructions = parser::parse(&source, Some(path));
+            self.sources.push((path.to_string(), source));
+            instructions
+        };
+        self.execute(instructions, out)
+    }
+
+    // Adds parsed instructions to the program and runs them, then shows the
+    // stack whether or not they succeeded.
+    fn execute(&mut self, instructions: Result<Vec<Instruction>, VmError>, out: &mut dyn Write) -> io::Result<()> {
+        if let Err(e) = instructions.and_then(|i| self.sm.extend(i)).and_then(|_| self.sm.run()) {
+            let file = e.location().file.as_deref();
+            let source = self.sources.iter().rev().find(|(name, _)| Some(name.as_str()) == file).map(|(_, text)| text.as_str());
+            write!(out, "{}", e.diagnostic(source))?;
+        }
+        self.show_stack(out)
+    }
+
+    fn show_stack(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "stack: {}", format_list(self.sm.stack()))
+    }
+}
@@ -0,0 +1,172 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use crate::error::{Location, VmError};
+use crate::instruction::InstructionType;
+use crate::machine::StackMachine;
+use crate::value::{format_nested, identical, Value};
+
+// Most values below the top of the stack any instruction other than `roll`
+// and `ret` can change. Values below that are never copied or compared, so
+// tracing costs the same however deep the stack is.
+const CHANGED_DEPTH: usize = 3;
+
+/// Runs a loaded program and writes one JSON object per executed instruction,
+/// one per line:
+///
+/// ```text
+/// {"step":3,"pointer":2,"line":4,"opcode":"addi","operand":null,"pop":2,"push":[3],"depth":1}
+/// ```
+///
+/// `step` counts every executed instruction from 1, traced or not, so records
+/// from two runs line up. `pop` and `push` give the change to the stack: how
+/// many values came off the top and the values that went on in their place.
+/// `depth` is the size of the stack afterwards.
+pub struct Tracer<'a> {
+    sm: &'a mut StackMachine,
+    out: Box<dyn Write>,
+    range: Range<usize>,
+}
+
+impl<'a> Tracer<'a> {
+    /// Traces every instruction of the program loaded into `sm` to `out`.
+    pub fn new(sm: &'a mut StackMachine, out: Box<dyn Write>) -> Tracer<'a> {
+        let range = 0..sm.instructions().len();
+        Tracer { sm, out, range }
+    }
+
+    /// Only traces instructions from the label `from` up to, but not
+    /// including, the label `to`. Either end can be left open.
+    pub fn trace_between(&mut self, from: Option<&str>, to: Option<&str>) -> Result<(), VmError> {
+        if let Some(from) = from {
+            self.range.start = self.label_index(from)?;
+        }
+        if let Some(to) = to {
+            self.range.end = self.label_index(to)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the program to the end, writing a record before moving on from
+    /// each traced instruction. The trace is flushed even if the program fails.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        let mut step = 0;
+        // Height of the stack when each active call started, which is as far
+        // down as its `ret` can change the stack.
+        let mut bases = Vec::new();
+        let result = loop {
+            if self.sm.is_halted() {
+                break self.sm.flush();
+            }
+            step += 1;
+            let pointer = self.sm.pointer();
+            let depth = self.sm.call_depth();
+            let traced = self.range.contains(&pointer);
+            let before = if traced { self.top(pointer, &bases) } else { (0, Vec::new()) };
+            if let Err(e) = self.sm.step() {
+                break Err(e);
+            }
+            match self.sm.call_depth() {
+                d if d > depth => bases.push(self.sm.stack().len()),
+                d if d < depth => {
+                    bases.pop();
+                },
+                _ => {},
+            }
+            if !traced {
+                continue;
+            }
+            let record = self.record(step, pointer, before);
+            if let Err(e) = writeln!(self.out, "{}", record) {
+                break Err(self.io_error(e));
+            }
+        };
+        let flushed = self.out.flush().map_err(|e| self.io_error(e));
+        result.and(flushed)
+    }
+
+    fn label_index(&self, label: &str) -> Result<usize, VmError> {
+        self.sm.instructions().iter().position(|i| i.instruction_type.defines_label() && i.label == label)
+            .ok_or_else(|| VmError::UndefinedLabel { label: label.to_string(), location: Location::default() })
+    }
+
+    // Copies the part of the stack the instruction at `pointer` can change,
+    // returning where it starts along with the values.
+    fn top(&self, pointer: usize, bases: &[usize]) -> (usize, Vec<Value>) {
+        let stack = self.sm.stack();
+        let depth = match self.sm.instructions()[pointer].instruction_type {
+            InstructionType::ROLL => match stack.last() {
+                Some(Value::INT(n)) => usize::try_from(*n).map_or(0, |n| n.saturating_add(2)),
+                _ => 0,
+            },
+            InstructionType::RET => bases.last().map_or(0, |&base| stack.len().saturating_sub(base) + 1),
+            _ => CHANGED_DEPTH,
+        };
+        let start = stack.len().saturating_sub(depth.max(CHANGED_DEPTH));
+        (start, stack[start..].to_vec())
+    }
+
+    // Builds the record for the instruction at `pointer`, which was just
+    // executed with the top of the stack from `start` holding `before`.
+    fn record(&self, step: usize, pointer: usize, (start, before): (usize, Vec<Value>)) -> String {
+        let instruction = &self.sm.instructions()[pointer];
+        let text = instruction.to_string();
+        let operand = match text.split_once(' ') {
+            Some((_, operand)) => json_string(operand),
+            None => String::from("null"),
+        };
+        let after = self.sm.stack();
+        let kept = match after.get(start..) {
+            Some(changed) => start + before.iter().zip(changed).take_while(|(a, b)| identical(a, b)).count(),
+            None => after.len(),
+        };
+        let pushed: Vec<String> = after[kept..].iter().map(json_value).collect();
+        format!("{{\"step\":{},\"pointer\":{},\"line\":{},\"opcode\":{},\"operand\":{},\"pop\":{},\"push\":[{}],\"depth\":{}}}",
+            step, pointer, instruction.location.line, json_string(instruction.instruction_type.mnemonic()), operand,
+            start + before.len() - kept, pushed.join(","), after.len())
+    }
+
+    fn io_error(&self, error: io::Error) -> VmError {
+        let location = self.sm.instructions().get(self.sm.pointer()).map(|i| i.location.clone()).unwrap_or_default();
+        VmError::IoError { message: error.to_string(), location }
+    }
+}
+
+// Writes a value as JSON. Numbers that JSON cannot represent, NaN and the
+// infinities, are written as strings.
+fn json_value(value: &Value) -> String {
+    match value {
+        Value::INT(v) => v.to_string(),
+        Value::LONG(v) => v.to_string(),
+        Value::BOOL(v) => v.to_string(),
+        Value::FLOAT(v) if v.is_finite() => format!("{:?}", v),
+        Value::DOUBLE(v) if v.is_finite() => format!("{:?}", v),
+        Value::FLOAT(_) | Value::DOUBLE(_) => json_string(&value.to_string()),
+        Value::STRING(v) => json_string(v),
+        Value::ARRAY(v) => {
+            let elements: Vec<Value> = (0..v.len()).filter_map(|i| v.get(i)).collect();
+            format_nested(&elements, ",", json_value)
+        },
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
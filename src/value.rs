@@ -0,0 +1,370 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::arithmetic::{ArithmeticMode, BinaryOp, UnaryOp};
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ValueType {
+    INT,
+    FLOAT,
+    BOOL,
+    LONG,
+    DOUBLE,
+    STRING,
+    ARRAY,
+}
+
+/// A value on the stack. Strings are immutable and shared, so copying one
+/// around the stack does not copy its text. Arrays are references to shared,
+/// mutable storage.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    INT(i32),
+    FLOAT(f32),
+    BOOL(bool),
+    LONG(i64),
+    DOUBLE(f64),
+    STRING(Rc<str>),
+    ARRAY(Array),
+}
+
+/// A reference to an array on the heap. Copies of the reference share the
+/// same elements, and the elements are freed when the last reference is
+/// dropped. The machine refuses to store an array inside itself, so reference
+/// counting never leaks a cycle. Arrays are only equal to themselves.
+#[derive(Clone)]
+pub struct Array(Rc<RefCell<Vec<Value>>>);
+
+/// Why an operation on values failed. The machine turns it into a `VmError`
+/// carrying the location of the instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueError {
+    TypeMismatch,
+    DivideByZero,
+    Overflow,
+    ShiftOutOfRange(i64),
+}
+
+impl Value {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::INT(_) => ValueType::INT,
+            Value::FLOAT(_) => ValueType::FLOAT,
+            Value::BOOL(_) => ValueType::BOOL,
+            Value::LONG(_) => ValueType::LONG,
+            Value::DOUBLE(_) => ValueType::DOUBLE,
+            Value::STRING(_) => ValueType::STRING,
+            Value::ARRAY(_) => ValueType::ARRAY,
+        }
+    }
+
+    /// Parses a literal of the given type, as written after a push. String
+    /// literals are double quoted.
+    pub fn parse(value_type: ValueType, text: &str) -> Option<Value> {
+        match value_type {
+            ValueType::INT => text.parse().ok().map(Value::INT),
+            ValueType::FLOAT => text.parse().ok().map(Value::FLOAT),
+            ValueType::BOOL => text.parse().ok().map(Value::BOOL),
+            ValueType::LONG => text.parse().ok().map(Value::LONG),
+            ValueType::DOUBLE => text.parse().ok().map(Value::DOUBLE),
+            ValueType::STRING => unquote(text).map(|text| Value::STRING(Rc::from(text))),
+            ValueType::ARRAY => None,
+        }
+    }
+
+    /// Whether the value counts as false for `jz` and `jnz`. Empty strings and
+    /// arrays count as false.
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Value::INT(v) => *v == 0,
+            Value::FLOAT(v) => *v == 0.0,
+            Value::BOOL(v) => !v,
+            Value::LONG(v) => *v == 0,
+            Value::DOUBLE(v) => *v == 0.0,
+            Value::STRING(v) => v.is_empty(),
+            Value::ARRAY(v) => v.is_empty(),
+        }
+    }
+
+    /// Applies `op` to two values of the same type. Adding two strings joins
+    /// them.
+    pub fn binary(self, op: BinaryOp, other: Value, mode: ArithmeticMode) -> Result<Value, ValueError> {
+        match (self, other) {
+            (Value::INT(a), Value::INT(b)) => op.apply(a, b, mode).map(Value::INT),
+            (Value::FLOAT(a), Value::FLOAT(b)) => op.apply(a, b, mode).map(Value::FLOAT),
+            (Value::LONG(a), Value::LONG(b)) => op.apply(a, b, mode).map(Value::LONG),
+            (Value::DOUBLE(a), Value::DOUBLE(b)) => op.apply(a, b, mode).map(Value::DOUBLE),
+            (Value::BOOL(a), Value::BOOL(b)) => match op {
+                BinaryOp::AND => Ok(Value::BOOL(a && b)),
+                BinaryOp::OR => Ok(Value::BOOL(a || b)),
+                _ => Err(ValueError::TypeMismatch),
+            },
+            (Value::STRING(a), Value::STRING(b)) if op == BinaryOp::ADD => Ok(Value::STRING(Rc::from([&*a, &*b].concat()))),
+            _ => Err(ValueError::TypeMismatch),
+        }
+    }
+
+    pub fn unary(self, op: UnaryOp, mode: ArithmeticMode) -> Result<Value, ValueError> {
+        match self {
+            Value::INT(a) => op.apply(a, mode).map(Value::INT),
+            Value::FLOAT(a) => op.apply(a, mode).map(Value::FLOAT),
+            Value::LONG(a) => op.apply(a, mode).map(Value::LONG),
+            Value::DOUBLE(a) => op.apply(a, mode).map(Value::DOUBLE),
+            Value::BOOL(a) if op == UnaryOp::NOT => Ok(Value::BOOL(!a)),
+            Value::BOOL(_) | Value::STRING(_) | Value::ARRAY(_) => Err(ValueError::TypeMismatch),
+        }
+    }
+
+    /// Converts a number to another numeric type. Integers are converted
+    /// exactly where possible and narrowing a long to an int follows `mode`.
+    /// Floats are truncated towards zero and clamped to the integer range,
+    /// with NaN becoming 0.
+    pub fn convert(self, to: ValueType, mode: ArithmeticMode) -> Result<Value, ValueError> {
+        let value = match (self, to) {
+            (Value::BOOL(_) | Value::STRING(_) | Value::ARRAY(_), _) | (_, ValueType::BOOL | ValueType::STRING | ValueType::ARRAY) => return Err(ValueError::TypeMismatch),
+            (Value::LONG(v), ValueType::INT) => Value::INT(mode.narrow(v).ok_or(ValueError::Overflow)?),
+            (Value::INT(v), _) => Value::from_f64_or_i64(to, v as f64, v as i64),
+            (Value::FLOAT(v), _) => Value::from_f64_or_i64(to, v as f64, v as i64),
+            (Value::LONG(v), _) => Value::from_f64_or_i64(to, v as f64, v),
+            (Value::DOUBLE(v), _) => Value::from_f64_or_i64(to, v, v as i64),
+        };
+        Ok(value)
+    }
+
+    // Builds a number of type `to` from a source value given both as a double
+    // and as a long, so integer targets never go through floating point.
+    fn from_f64_or_i64(to: ValueType, double: f64, long: i64) -> Value {
+        match to {
+            ValueType::INT => Value::INT(double as i32),
+            ValueType::FLOAT => Value::FLOAT(double as f32),
+            ValueType::LONG => Value::LONG(long),
+            ValueType::DOUBLE => Value::DOUBLE(double),
+            ValueType::BOOL | ValueType::STRING | ValueType::ARRAY => unreachable!(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::INT(v) => write!(f, "{}", v),
+            Value::FLOAT(v) => write!(f, "{}", v),
+            Value::BOOL(v) => write!(f, "{}", v),
+            Value::LONG(v) => write!(f, "{}", v),
+            Value::DOUBLE(v) => write!(f, "{}", v),
+            Value::STRING(v) => write!(f, "{}", v),
+            Value::ARRAY(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// Values are only ordered against values of the same type; NaN is not
+/// ordered against anything.
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::INT(a), Value::INT(b)) => a.partial_cmp(b),
+            (Value::FLOAT(a), Value::FLOAT(b)) => a.partial_cmp(b),
+            (Value::BOOL(a), Value::BOOL(b)) => a.partial_cmp(b),
+            (Value::LONG(a), Value::LONG(b)) => a.partial_cmp(b),
+            (Value::DOUBLE(a), Value::DOUBLE(b)) => a.partial_cmp(b),
+            (Value::STRING(a), Value::STRING(b)) => a.partial_cmp(b),
+            (Value::ARRAY(a), Value::ARRAY(b)) if a == b => Some(Ordering::Equal),
+            _ => None,
+        }
+    }
+}
+
+impl Array {
+    /// Creates an array of `length` ints set to 0, or `None` if there is not
+    /// enough memory for it.
+    pub fn new(length: usize) -> Option<Array> {
+        let mut elements = Vec::new();
+        elements.try_reserve_exact(length).ok()?;
+        elements.resize(length, Value::INT(0));
+        Some(Array(Rc::new(RefCell::new(elements))))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<Value> {
+        self.0.borrow().get(index).cloned()
+    }
+
+    /// Replaces the element at `index`, returning false if it is out of range.
+    pub fn set(&self, index: usize, value: Value) -> bool {
+        match self.0.borrow_mut().get_mut(index) {
+            Some(element) => {
+                *element = value;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Whether `other` is this array or is held by it, directly or through
+    /// nested arrays. Arrays are walked with a worklist rather than
+    /// recursion, so any depth of nesting can be checked.
+    pub fn contains(&self, other: &Array) -> bool {
+        let mut visited = HashSet::new();
+        let mut pending = vec![self.clone()];
+        while let Some(array) = pending.pop() {
+            if array == *other {
+                return true;
+            }
+            if visited.insert(Rc::as_ptr(&array.0)) {
+                pending.extend(array.0.borrow().iter().filter_map(|v| match v {
+                    Value::ARRAY(inner) => Some(inner.clone()),
+                    _ => None,
+                }));
+            }
+        }
+        false
+    }
+}
+
+// Freeing an array frees the arrays only it holds. Doing that one level at a
+// time from a worklist, instead of letting each drop recurse into the next,
+// keeps deep nesting from overflowing the native stack.
+impl Drop for Array {
+    fn drop(&mut self) {
+        if Rc::strong_count(&self.0) != 1 {
+            return;
+        }
+        let Ok(mut elements) = self.0.try_borrow_mut() else { return };
+        let mut pending: Vec<Array> = take_arrays(&mut elements);
+        drop(elements);
+        while let Some(array) = pending.pop() {
+            if Rc::strong_count(&array.0) == 1 {
+                if let Ok(mut elements) = array.0.try_borrow_mut() {
+                    pending.extend(take_arrays(&mut elements));
+                }
+            }
+        }
+    }
+}
+
+// Moves the arrays out of `elements`, leaving ints in their place.
+fn take_arrays(elements: &mut [Value]) -> Vec<Array> {
+    elements.iter_mut().filter_map(|v| match std::mem::replace(v, Value::INT(0)) {
+        Value::ARRAY(inner) => Some(inner),
+        other => {
+            *v = other;
+            None
+        },
+    }).collect()
+}
+
+impl PartialEq for Array {
+    fn eq(&self, other: &Array) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for Array {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Array({})", self)
+    }
+}
+
+impl fmt::Display for Array {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format_list(&self.0.borrow()))
+    }
+}
+
+// Whether two values are the same, comparing floats and doubles by bit
+// pattern so 0.0 and -0.0 stay distinct and a NaN matches itself. Constants
+// are pooled this way and the tracer finds what changed on the stack with it.
+pub(crate) fn identical(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::FLOAT(a), Value::FLOAT(b)) => a.to_bits() == b.to_bits(),
+        (Value::DOUBLE(a), Value::DOUBLE(b)) => a.to_bits() == b.to_bits(),
+        _ => a == b,
+    }
+}
+
+// Writes values the way an array prints, `[a, b]`. The debugger and the REPL
+// show the stack this way too.
+pub(crate) fn format_list(values: &[Value]) -> String {
+    format_nested(values, ", ", |v| v.to_string())
+}
+
+// Writes values in brackets, separated by `separator`, with nested arrays in
+// brackets of their own and everything else written by `scalar`. Nested
+// arrays are followed with a worklist rather than recursion, so any depth of
+// nesting can be written.
+pub(crate) fn format_nested(values: &[Value], separator: &str, scalar: impl Fn(&Value) -> String) -> String {
+    let mut out = String::from("[");
+    let mut pending = vec![Vec::from(values).into_iter()];
+    let mut first = true;
+    while let Some(elements) = pending.last_mut() {
+        match elements.next() {
+            Some(value) => {
+                if !first {
+                    out.push_str(separator);
+                }
+                first = match value {
+                    Value::ARRAY(inner) => {
+                        out.push('[');
+                        let elements = inner.0.borrow().to_vec();
+                        pending.push(elements.into_iter());
+                        true
+                    },
+                    scalar_value => {
+                        out.push_str(&scalar(&scalar_value));
+                        false
+                    },
+                };
+            },
+            None => {
+                pending.pop();
+                out.push(']');
+                first = false;
+            },
+        }
+    }
+    out
+}
+
+// Reads a double quoted string literal. The escapes are the ones `{:?}`
+// produces for a `str`, so a disassembled literal reads back the same:
+// `\n`, `\r`, `\t`, `\0`, `\\`, `\"`, `\'` and `\u{...}`.
+fn unquote(text: &str) -> Option<String> {
+    let inner = text.strip_prefix('"')?.strip_suffix('"')?;
+    let mut result = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return None,
+            '\\' => result.push(match chars.next()? {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                '0' => '\0',
+                '\\' => '\\',
+                '"' => '"',
+                '\'' => '\'',
+                'u' => {
+                    let rest = chars.as_str().strip_prefix('{')?;
+                    let end = rest.find('}')?;
+                    let code = u32::from_str_radix(&rest[..end], 16).ok()?;
+                    chars = rest[end + 1..].chars();
+                    char::from_u32(code)?
+                },
+                _ => return None,
+            }),
+            c => result.push(c),
+        }
+    }
+    Some(result)
+}
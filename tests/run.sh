@@ -1,7 +1,36 @@
-files=("test1" "test2" "test4" "test5" "test6" "test7" "test8" "test9")
+files=("test1" "test2" "test4" "test5" "test6" "test7" "test8" "test9" "test11" "test12" "test13" "test14" "test15" "test16" "test17" "test18" "test19" "test20" "test21" "test22" "test23" "test24" "test25" "test26" "test27" "test28" "test29" "test30" "test31" "test32" "test33" "test34" "test35" "test36" "test37" "test38" "test39" "test40" "test41" "test42" "test43" "test44" "test45" "test46" "test47" "test48" "test49" "test50" "test51" "test52" "test53" "test54" "test55" "test56" "test57" "test58")
 for i in "${files[@]}"
 do
-	../target/debug/huw-stack-machine "$i.hsc" > "$i".output
+	# Extra command line options for a test are read from its .args file and
+	# its standard input from its .input file. Tests without a .hsc file run
+	# the REPL on their input.
+	input="$i.input"
+	[ -f "$input" ] || input=/dev/null
+	program="$i.hsc"
+	[ -f "$program" ] || program=
+	../target/debug/huw-stack-machine $(cat "$i.args" 2>/dev/null) $program < "$input" > "$i".output 2>&1
+	status=$?
   diff -q "$i.expected" "$i".output
+	# Tests with a .status file also check the exit status.
+	[ -f "$i.status" ] && [ "$(cat "$i.status")" != "$status" ] && echo "$i exited with status $status"
+	# Tests that write a trace or a profile to a file also check it.
+	for extension in trace folded
+	do
+		[ -f "$i.$extension.expected" ] && diff -q "$i.$extension.expected" "$i.$extension"
+	done
 done
-rm -rf *.output
+rm -rf *.output *.trace *.folded
+
+# Assemble each program to bytecode, check that disassembling and assembling
+# again gives the same bytes and that the bytecode runs the same way.
+roundtrip=("test1" "test2" "test4" "test5" "test6" "test7" "test8" "test9" "test15" "test16" "test25" "test29" "test31" "test34" "test36" "test38")
+for i in "${roundtrip[@]}"
+do
+	../target/debug/huw-stack-machine assemble "$i.hsc" "$i.hsb"
+	../target/debug/huw-stack-machine disassemble "$i.hsb" > "$i".dis.hsc
+	../target/debug/huw-stack-machine assemble "$i.dis.hsc" "$i".dis.hsb
+  cmp -s "$i.hsb" "$i.dis.hsb" || echo "Files $i.hsb and $i.dis.hsb differ"
+	../target/debug/huw-stack-machine "$i.hsb" > "$i".output 2>&1
+  diff -q "$i.expected" "$i".output
+done
+rm -rf *.output *.hsb *.dis.hsc